@@ -0,0 +1,2 @@
+ALTER TABLE entries ADD COLUMN expires_at TIMESTAMPTZ;
+CREATE INDEX entries_expires_at ON entries (expires_at) WHERE expires_at IS NOT NULL;
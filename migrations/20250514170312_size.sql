@@ -0,0 +1,2 @@
+ALTER TABLE entries ADD COLUMN size BIGINT;
+UPDATE entries SET size = octet_length(value) WHERE encoding IS NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE entries ADD COLUMN hash BYTEA;
+CREATE INDEX entries_hash ON entries (hash);
@@ -0,0 +1,5 @@
+ALTER TABLE entries ADD COLUMN created_at TIMESTAMPTZ DEFAULT now() NOT NULL;
+-- the first 48 bits of a ulid are its creation time in milliseconds
+UPDATE entries SET created_at = to_timestamp(
+	('x' || substr(replace(id::text, '-', ''), 1, 12))::bit(48)::bigint / 1000.0
+);
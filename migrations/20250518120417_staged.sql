@@ -0,0 +1,6 @@
+-- values of uploads that are still being received, moved to entries once complete
+CREATE UNLOGGED TABLE staged (
+	id UUID PRIMARY KEY,
+	value BYTEA NOT NULL,
+	updated_at TIMESTAMPTZ DEFAULT now() NOT NULL
+);
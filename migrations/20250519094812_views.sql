@@ -0,0 +1 @@
+ALTER TABLE entries ADD COLUMN views BIGINT DEFAULT 0 NOT NULL;
@@ -0,0 +1,2 @@
+-- values of encrypted entries are the nonce followed by the ciphertext
+ALTER TABLE entries ADD COLUMN encrypted BOOLEAN DEFAULT false NOT NULL;
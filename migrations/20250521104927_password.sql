@@ -0,0 +1,2 @@
+-- argon2 hash in the phc string format, entries without one are public
+ALTER TABLE entries ADD COLUMN password_hash TEXT;
@@ -0,0 +1,3 @@
+-- set instead of deleting the row with `SOFT_DELETE`, purged once it is old enough
+ALTER TABLE entries ADD COLUMN deleted_at TIMESTAMPTZ;
+CREATE INDEX entries_deleted_at ON entries (deleted_at) WHERE deleted_at IS NOT NULL;
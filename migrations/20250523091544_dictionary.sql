@@ -0,0 +1,2 @@
+-- id of the zstd dictionary the value was compressed with, if any
+ALTER TABLE entries ADD COLUMN dictionary_id BIGINT;
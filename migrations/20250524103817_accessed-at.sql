@@ -0,0 +1,3 @@
+-- when the entry was last retrieved, to evict the least recently used ones with `MAX_ENTRIES`
+ALTER TABLE entries ADD COLUMN accessed_at TIMESTAMPTZ;
+CREATE INDEX entries_accessed_at ON entries ((COALESCE(accessed_at, created_at)));
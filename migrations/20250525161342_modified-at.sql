@@ -0,0 +1,2 @@
+-- when the value was last replaced with `ALLOW_PATCH`, for `Last-Modified` and the etag
+ALTER TABLE entries ADD COLUMN modified_at TIMESTAMPTZ;
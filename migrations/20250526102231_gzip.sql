@@ -0,0 +1,2 @@
+-- the value compressed with gzip ahead of time with `PRECOMPUTE_GZIP`, encrypted like `value`
+ALTER TABLE entries ADD COLUMN gzip BYTEA;
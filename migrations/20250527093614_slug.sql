@@ -0,0 +1,3 @@
+-- the short id an entry is shared as with `ID_MODE=short`
+ALTER TABLE entries ADD COLUMN slug TEXT;
+CREATE UNIQUE INDEX entries_slug ON entries (slug);
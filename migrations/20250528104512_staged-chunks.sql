@@ -0,0 +1,11 @@
+-- each write to a staged upload becomes its own row, so appending doesn't
+-- rewrite everything received so far. uploads still being received are lost,
+-- but their sessions don't survive the restart this comes with anyway
+DELETE FROM staged;
+ALTER TABLE staged DROP COLUMN value;
+CREATE UNLOGGED TABLE staged_chunks (
+	id UUID NOT NULL REFERENCES staged (id) ON DELETE CASCADE,
+	seq INTEGER NOT NULL,
+	value BYTEA NOT NULL,
+	PRIMARY KEY (id, seq)
+);
@@ -0,0 +1,41 @@
+use std::io;
+
+use tar::{EntryType, Header};
+
+use crate::encoding::{Compressor, Encoding};
+
+const BLOCK: usize = 512;
+
+/// writes a gzipped tarball a file at a time, handing out what is compressed so far
+pub struct TarGz(Compressor);
+
+impl TarGz {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self(Encoding::Gzip.compressor()?))
+    }
+
+    /// adds a file modified at the unix timestamp `mtime`, returning the part
+    /// of the archive compressed since the last call
+    pub fn append(&mut self, path: &str, mtime: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut header = Header::new_ustar();
+        header.set_path(path)?;
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_entry_type(EntryType::Regular);
+        header.set_cksum();
+        self.0.write(header.as_bytes())?;
+        self.0.write(data)?;
+        // files are padded to whole blocks
+        self.0
+            .write(&[0; BLOCK][..data.len().next_multiple_of(BLOCK) - data.len()])?;
+        Ok(self.0.take_output())
+    }
+
+    /// ends the archive, returning the rest of it
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        // two empty blocks mark the end of a tarball
+        self.0.write(&[0; BLOCK * 2])?;
+        self.0.finish()
+    }
+}
@@ -0,0 +1,165 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::header::HeaderName;
+use axum::http::request::Parts;
+use thiserror::Error;
+
+use crate::AppState;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+#[derive(Error, Debug)]
+#[error("invalid cidr `{0}`")]
+pub struct InvalidCidr(String);
+
+/// a range of addresses like `10.0.0.0/8`, or a single address
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(addr) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(addr) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// who is trusted to say which ip a request came from
+#[derive(Clone, Debug, Default)]
+pub enum ProxyTrust {
+    /// only the peer address counts, forwarding headers are ignored
+    #[default]
+    None,
+    /// any peer is a proxy, and the last `X-Forwarded-For` entry is the client
+    Any,
+    /// only peers in these ranges are, and `X-Forwarded-For` is followed back
+    /// past every proxy in them
+    Cidrs(Arc<[Cidr]>),
+}
+
+impl ProxyTrust {
+    fn trusts(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::None => false,
+            Self::Any => true,
+            Self::Cidrs(cidrs) => cidrs.iter().any(|cidr| cidr.contains(ip)),
+        }
+    }
+}
+
+/// one entry of `X-Forwarded-For`, which some proxies add a port to
+fn parse_forwarded(v: &str) -> Option<IpAddr> {
+    let v = v.trim();
+    v.parse()
+        .ok()
+        .or_else(|| v.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .map(|ip: IpAddr| ip.to_canonical())
+}
+
+/// the ip of the client, from forwarding headers if the peer is a trusted proxy
+///
+/// without a peer address, as on unix sockets, the peer is assumed to be a
+/// proxy if any are trusted.
+fn client_ip(parts: &Parts, trust: &ProxyTrust) -> Option<IpAddr> {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    if matches!(trust, ProxyTrust::None) || peer.is_some_and(|peer| !trust.trusts(peer)) {
+        return peer;
+    }
+
+    let mut forwarded = parts
+        .headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter(|v| !v.trim().is_empty())
+        .peekable();
+    if forwarded.peek().is_none() {
+        let real_ip = parts
+            .headers
+            .get(X_REAL_IP)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_forwarded);
+        return real_ip.or(peer);
+    }
+
+    // walk back from the closest hop until one isn't ours, anything before
+    // that could have been made up by the client
+    let mut closest = peer;
+    for hop in forwarded.rev() {
+        let Some(ip) = parse_forwarded(hop) else {
+            break;
+        };
+        if !trust.trusts(ip) {
+            return Some(ip);
+        }
+        closest = Some(ip);
+        if matches!(trust, ProxyTrust::Any) {
+            break;
+        }
+    }
+    closest
+}
+
+/// the ip of the client, `None` if it can't be known, as on unix sockets without a proxy
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(client_ip(parts, &state.proxy_trust)))
+    }
+}
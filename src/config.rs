@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use color_eyre::eyre::{self, Context};
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("failed to parse {0}: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("`{0}` must be a string, number or boolean")]
+    InvalidValue(String),
+}
+
+/// settings from the environment, falling back to the file in `CONFIG_FILE`
+#[derive(Default, Debug)]
+pub struct Settings {
+    file: HashMap<String, String>,
+}
+
+impl Settings {
+    /// reads `CONFIG_FILE`, a json object keyed by the names of the environment variables
+    ///
+    /// `null` values are the same as leaving the setting out.
+    pub fn load() -> Result<Self, ConfigError> {
+        let Some(path) = std::env::var_os("CONFIG_FILE").map(PathBuf::from) else {
+            return Ok(Self::default());
+        };
+        let data = std::fs::read(&path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+        let values: HashMap<String, Value> =
+            serde_json::from_slice(&data).map_err(|err| ConfigError::Parse(path, err))?;
+
+        let mut file = HashMap::with_capacity(values.len());
+        for (name, value) in values {
+            let value = match value {
+                Value::Null => continue,
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                Value::Bool(value) => value.to_string(),
+                Value::Array(_) | Value::Object(_) => return Err(ConfigError::InvalidValue(name)),
+            };
+            file.insert(name, value);
+        }
+        Ok(Self { file })
+    }
+
+    pub fn var(&self, name: &str) -> Option<String> {
+        std::env::var(name)
+            .ok()
+            .or_else(|| self.file.get(name).cloned())
+    }
+
+    pub fn var_os(&self, name: &str) -> Option<OsString> {
+        std::env::var_os(name).or_else(|| self.file.get(name).map(OsString::from))
+    }
+
+    /// parses the setting `name`, if it is set
+    pub fn parse<T>(&self, name: &str) -> eyre::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Error + Send + Sync + 'static,
+    {
+        self.var(name)
+            .map(|v| {
+                v.parse()
+                    .with_context(|| format!("failed to parse `{name}`"))
+            })
+            .transpose()
+    }
+}
@@ -0,0 +1,125 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::extract::{FromRequestParts, Request};
+use axum::http::header::{
+    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    HeaderValue, InvalidHeaderValue, ORIGIN, VARY,
+};
+use axum::http::request::Parts;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, IntoResponseParts, Response, ResponseParts};
+
+use crate::AppState;
+
+/// origins allowed to make cross-origin requests
+#[derive(Clone, Debug)]
+pub enum AllowedOrigins {
+    Any,
+    List(Arc<[HeaderValue]>),
+}
+
+impl FromStr for AllowedOrigins {
+    type Err = InvalidHeaderValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "*" {
+            return Ok(Self::Any);
+        }
+        s.split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(HeaderValue::from_str)
+            .collect::<Result<_, _>>()
+            .map(Self::List)
+    }
+}
+
+/// in the same form as `CORS_ORIGIN`
+impl fmt::Display for AllowedOrigins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => f.write_str("*"),
+            Self::List(origins) => {
+                for (i, origin) in origins.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    f.write_str(&String::from_utf8_lossy(origin.as_bytes()))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// the cors headers for a response to the request this was extracted from
+///
+/// `Access-Control-Allow-Origin` is only set if the `Origin` of the request is allowed.
+pub struct Cors {
+    allow_origin: Option<HeaderValue>,
+    vary: bool,
+    credentials: bool,
+}
+
+impl FromRequestParts<AppState> for Cors {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let credentials = state.cors_credentials;
+        Ok(match &state.allowed_origins {
+            AllowedOrigins::Any => Self {
+                allow_origin: Some(HeaderValue::from_static("*")),
+                vary: false,
+                credentials,
+            },
+            AllowedOrigins::List(origins) => Self {
+                allow_origin: parts
+                    .headers
+                    .get(ORIGIN)
+                    .filter(|origin| origins.contains(origin))
+                    .cloned(),
+                vary: true,
+                credentials,
+            },
+        })
+    }
+}
+
+impl IntoResponseParts for Cors {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        let headers = res.headers_mut();
+        if let Some(allow_origin) = self.allow_origin {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            // the request id so the frontend can show it when something goes
+            // wrong, and the offset for resuming uploads
+            headers.insert(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static("x-request-id, upload-offset"),
+            );
+            if self.credentials {
+                headers.insert(
+                    ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+            }
+        }
+        // appended, so this must come after any other `Vary` header in the response
+        if self.vary {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+        Ok(res)
+    }
+}
+
+/// adds the cors headers to every response, including errors and rejections
+pub async fn add_headers(cors: Cors, request: Request, next: Next) -> Response {
+    (cors, next.run(request).await).into_response()
+}
@@ -0,0 +1,69 @@
+use std::fmt::{self, Debug};
+use std::str::FromStr;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use thiserror::Error;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// how much longer a value gets when encrypted
+pub const OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("key is not valid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("key must be 32 bytes, not {0}")]
+    Length(usize),
+}
+
+#[derive(Error, Debug)]
+#[error("failed to decrypt value")]
+pub struct DecryptionError;
+
+/// encrypts values with aes-256-gcm, prefixing each with its random nonce
+#[derive(Clone)]
+pub struct Cipher(Aes256Gcm);
+
+impl Cipher {
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, data)
+            .expect("values are far too small to fail encrypting");
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if data.len() < NONCE_LEN {
+            return Err(DecryptionError);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| DecryptionError)
+    }
+}
+
+/// parses a base64 encoded 256 bit key
+impl FromStr for Cipher {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = STANDARD.decode(s.trim())?;
+        if key.len() != 32 {
+            return Err(KeyError::Length(key.len()));
+        }
+        Ok(Self(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))))
+    }
+}
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher { .. }")
+    }
+}
@@ -0,0 +1,64 @@
+use axum::http::header::{HeaderMap, HeaderName, HeaderValue};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use md5::{Digest, Md5};
+
+/// `sha-256=…` and `md5=…`, both base64, as in rfc 3230
+pub const DIGEST: HeaderName = HeaderName::from_static("digest");
+pub const CONTENT_MD5: HeaderName = HeaderName::from_static("content-md5");
+
+/// a digest header that isn't valid base64 of a digest of the right length
+#[derive(Debug)]
+pub struct InvalidDigest;
+
+/// the digests a client sent with its upload, of the value once decompressed
+#[derive(Default, Debug)]
+pub struct Digests {
+    sha256: Option<Vec<u8>>,
+    md5: Option<Vec<u8>>,
+}
+
+fn decode(digest: &str, length: usize) -> Result<Vec<u8>, InvalidDigest> {
+    STANDARD
+        .decode(digest.trim())
+        .ok()
+        .filter(|digest| digest.len() == length)
+        .ok_or(InvalidDigest)
+}
+
+impl Digests {
+    /// the digests in `Digest` and `Content-MD5`, other algorithms are ignored
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, InvalidDigest> {
+        let mut digests = Self::default();
+        for value in headers.get_all(DIGEST) {
+            let value = value.to_str().map_err(|_| InvalidDigest)?;
+            for digest in value.split(',').filter(|v| !v.trim().is_empty()) {
+                let (algorithm, digest) = digest.split_once('=').ok_or(InvalidDigest)?;
+                match algorithm.trim().to_ascii_lowercase().as_str() {
+                    "sha-256" => digests.sha256 = Some(decode(digest, 32)?),
+                    "md5" => digests.md5 = Some(decode(digest, 16)?),
+                    _ => {}
+                }
+            }
+        }
+        if let Some(value) = headers.get(CONTENT_MD5).map(HeaderValue::to_str) {
+            digests.md5 = Some(decode(value.map_err(|_| InvalidDigest)?, 16)?);
+        }
+        Ok(digests)
+    }
+
+    /// a hasher to feed the value to, if there is an md5 to check it against
+    pub fn md5(&self) -> Option<Md5> {
+        self.md5.as_ref().map(|_| Md5::new())
+    }
+
+    /// whether the sha-256 of the value and the hasher from `md5` match what was sent
+    pub fn matches(&self, sha256: &[u8], md5: Option<Md5>) -> bool {
+        let md5 = md5.map(|md5| md5.finalize().to_vec());
+        self.sha256.as_deref().is_none_or(|sent| sent == sha256)
+            && self
+                .md5
+                .as_deref()
+                .is_none_or(|sent| md5.as_deref() == Some(sent))
+    }
+}
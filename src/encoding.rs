@@ -0,0 +1,360 @@
+use std::borrow::Cow;
+use std::fmt::{self, Debug};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use brotli::enc::BrotliEncoderParams;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use thiserror::Error;
+
+/// the default of 11 is far too slow to compress responses on the fly
+const BROTLI_QUALITY: i32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+/// what trained zstd dictionaries start with, followed by their id
+const DICTIONARY_MAGIC: [u8; 4] = 0xec30a437_u32.to_le_bytes();
+
+#[derive(Error, Debug)]
+#[error("unknown encoding `{0}`")]
+pub struct UnknownEncoding(pub String);
+
+#[derive(Error, Debug)]
+pub enum DictionaryError {
+    #[error("not a trained zstd dictionary")]
+    Invalid,
+    #[error("dictionary has no id")]
+    MissingId,
+    #[error("there already is a dictionary with id {0}")]
+    DuplicateId(u32),
+}
+
+/// compression applied to a stored entry or a response
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+/// encodings we are willing to compress responses with, in order of preference
+const RESPONSE_ENCODINGS: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+impl Encoding {
+    /// parses the value of the `encoding` column, where `NULL` means uncompressed
+    pub fn from_column(value: Option<&str>) -> Result<Self, UnknownEncoding> {
+        value.map_or(Ok(Self::Identity), str::parse)
+    }
+
+    /// the content coding as used in `Content-Encoding`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Identity => "identity",
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// the value stored in the `encoding` column
+    pub fn to_column(self) -> Option<&'static str> {
+        match self {
+            Self::Identity => None,
+            _ => Some(self.as_str()),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            Self::Identity => Ok(Cow::Borrowed(data)),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish().map(Cow::Owned)
+            }
+            Self::Brotli => {
+                let mut out = Vec::new();
+                let params = BrotliEncoderParams {
+                    quality: BROTLI_QUALITY,
+                    ..Default::default()
+                };
+                brotli::BrotliCompress(&mut &*data, &mut out, &params)?;
+                Ok(Cow::Owned(out))
+            }
+            Self::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL).map(Cow::Owned),
+        }
+    }
+
+    /// starts compressing a value that comes in chunks
+    pub fn compressor(self) -> io::Result<Compressor> {
+        let output = SharedBuffer::default();
+        let writer = match self {
+            Self::Identity => Writer::Identity,
+            Self::Gzip => Writer::Gzip(GzEncoder::new(output.clone(), Compression::default())),
+            Self::Brotli => Writer::Brotli(Box::new(brotli::CompressorWriter::new(
+                output.clone(),
+                4096,
+                BROTLI_QUALITY as u32,
+                BROTLI_WINDOW,
+            ))),
+            Self::Zstd => Writer::Zstd(zstd::Encoder::new(
+                output.clone(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?),
+        };
+        Ok(Compressor { writer, output })
+    }
+
+    pub fn decompress(self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Identity => Ok(data),
+            Self::Gzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(&*data).read_to_end(&mut out)?;
+                Ok(out)
+            }
+            Self::Brotli => {
+                let mut out = Vec::new();
+                brotli::BrotliDecompress(&mut &*data, &mut out)?;
+                Ok(out)
+            }
+            Self::Zstd => zstd::decode_all(&*data),
+        }
+    }
+
+    /// decompresses `data`, giving up with `None` once it grows past `limit` bytes
+    pub fn decompress_limited(self, data: &[u8], limit: usize) -> io::Result<Option<Vec<u8>>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::Identity => Box::new(data),
+            Self::Gzip => Box::new(GzDecoder::new(data)),
+            Self::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            Self::Zstd => Box::new(zstd::Decoder::new(data)?),
+        };
+        let mut out = Vec::new();
+        reader.take(limit as u64 + 1).read_to_end(&mut out)?;
+        Ok((out.len() <= limit).then_some(out))
+    }
+}
+
+/// a trained zstd dictionary, which shrinks small values a lot more than plain zstd
+pub struct Dictionary {
+    id: u32,
+    data: Vec<u8>,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> Result<Self, DictionaryError> {
+        if !data.starts_with(&DICTIONARY_MAGIC) {
+            return Err(DictionaryError::Invalid);
+        }
+        let id = data[DICTIONARY_MAGIC.len()..]
+            .first_chunk()
+            .map(|id| u32::from_le_bytes(*id))
+            .ok_or(DictionaryError::Invalid)?;
+        // entries only record the id, so it has to tell dictionaries apart
+        if id == 0 {
+            return Err(DictionaryError::MissingId);
+        }
+        Ok(Self { id, data })
+    }
+
+    /// the id written in the header of the dictionary
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = zstd::Encoder::with_dictionary(
+            Vec::new(),
+            zstd::DEFAULT_COMPRESSION_LEVEL,
+            &self.data,
+        )?;
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    /// like [`Encoding::compressor`]
+    pub fn compressor(&self) -> io::Result<Compressor> {
+        let output = SharedBuffer::default();
+        let writer = Writer::Zstd(zstd::Encoder::with_dictionary(
+            output.clone(),
+            zstd::DEFAULT_COMPRESSION_LEVEL,
+            &self.data,
+        )?);
+        Ok(Compressor { writer, output })
+    }
+
+    pub fn decompress(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        zstd::Decoder::with_dictionary(&*data, &self.data)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+impl Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// the dictionaries from `ZSTD_DICT`
+///
+/// new entries are compressed with the first one, the others are only kept
+/// around to read entries compressed with them before.
+#[derive(Default, Debug)]
+pub struct Dictionaries(Vec<Dictionary>);
+
+impl Dictionaries {
+    pub fn new(dictionaries: Vec<Dictionary>) -> Result<Self, DictionaryError> {
+        for (i, dictionary) in dictionaries.iter().enumerate() {
+            if dictionaries[..i].iter().any(|d| d.id == dictionary.id) {
+                return Err(DictionaryError::DuplicateId(dictionary.id));
+            }
+        }
+        Ok(Self(dictionaries))
+    }
+
+    /// the dictionary to compress new entries with
+    pub fn current(&self) -> Option<&Dictionary> {
+        self.0.first()
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Dictionary> {
+        self.0.iter().find(|dictionary| dictionary.id == id)
+    }
+}
+
+/// a buffer the output of a compressor can be taken out of while the compressor owns it
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        mem::take(&mut self.0.lock().expect("compressor output lock poisoned"))
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .expect("compressor output lock poisoned")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Writer {
+    Identity,
+    Gzip(GzEncoder<SharedBuffer>),
+    Brotli(Box<brotli::CompressorWriter<SharedBuffer>>),
+    Zstd(zstd::Encoder<'static, SharedBuffer>),
+}
+
+/// compresses a value chunk by chunk, see [`Encoding::compressor`]
+pub struct Compressor {
+    writer: Writer,
+    output: SharedBuffer,
+}
+
+impl Compressor {
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.writer {
+            Writer::Identity => self.output.write_all(data),
+            Writer::Gzip(writer) => writer.write_all(data),
+            Writer::Brotli(writer) => writer.write_all(data),
+            Writer::Zstd(writer) => writer.write_all(data),
+        }
+    }
+
+    /// takes the compressed output produced so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.take()
+    }
+
+    /// finishes compressing, returning the rest of the output
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self.writer {
+            Writer::Identity => {}
+            Writer::Gzip(writer) => {
+                writer.finish()?;
+            }
+            Writer::Brotli(writer) => {
+                writer.into_inner();
+            }
+            Writer::Zstd(writer) => {
+                writer.finish()?;
+            }
+        }
+        Ok(self.output.take())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = UnknownEncoding;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" | "identity" => Ok(Self::Identity),
+            "gzip" => Ok(Self::Gzip),
+            "br" => Ok(Self::Brotli),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(UnknownEncoding(s.to_string())),
+        }
+    }
+}
+
+/// picks the encoding of a response to a request with the given `Accept-Encoding`
+/// header, reusing the encoding of the stored entry if the client accepts it
+pub fn negotiate(accept_encoding: Option<&str>, stored: Encoding) -> Encoding {
+    let Some(accept_encoding) = accept_encoding else {
+        return Encoding::Identity;
+    };
+
+    let mut wildcard = None;
+    let mut accepted = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';').map(str::trim);
+        let coding = params.next().unwrap_or_default();
+        let quality = params
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if coding == "*" {
+            wildcard = Some(quality);
+        } else if let Ok(encoding) = coding.to_ascii_lowercase().parse::<Encoding>() {
+            accepted.push((encoding, quality));
+        }
+    }
+    let quality = |encoding: Encoding| {
+        accepted
+            .iter()
+            .find(|(accepted, _)| *accepted == encoding)
+            .map(|(_, quality)| *quality)
+            .or(wildcard)
+            .unwrap_or(0.0)
+    };
+
+    if stored != Encoding::Identity && quality(stored) > 0.0 {
+        return stored;
+    }
+
+    let mut best = (Encoding::Identity, 0.0);
+    for encoding in RESPONSE_ENCODINGS {
+        let quality = quality(encoding);
+        if quality > best.1 {
+            best = (encoding, quality);
+        }
+    }
+    best.0
+}
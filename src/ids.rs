@@ -0,0 +1,82 @@
+use std::fmt::{self, Debug};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use thiserror::Error;
+use ulid::{Generator, Ulid};
+
+const SLUG_ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+/// 62^8 slugs, about 48 bits' worth
+const SLUG_LEN: usize = 8;
+
+/// what entries are shared as
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdMode {
+    /// their ulid
+    Ulid,
+    /// a random base62 slug pointing to their ulid, which is still accepted
+    Short,
+}
+
+#[derive(Error, Debug)]
+#[error("unknown id mode `{0}`")]
+pub struct UnknownIdMode(String);
+
+impl FromStr for IdMode {
+    type Err = UnknownIdMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ulid" => Ok(Self::Ulid),
+            "short" => Ok(Self::Short),
+            _ => Err(UnknownIdMode(s.to_owned())),
+        }
+    }
+}
+
+/// a new random slug, which is too short to be mistaken for a ulid or uuid
+pub fn new_slug() -> String {
+    let mut n = OsRng.next_u64();
+    (0..SLUG_LEN)
+        .map(|_| {
+            let c = SLUG_ALPHABET[(n % 62) as usize];
+            n /= 62;
+            char::from(c)
+        })
+        .collect()
+}
+
+/// whether `s` could be a slug from `new_slug`
+pub fn is_slug(s: &str) -> bool {
+    s.len() == SLUG_LEN && s.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// makes ids for new entries, each after the one before it even within the same
+/// millisecond, which keeps inserts at the end of the primary key index
+pub struct Ids(Mutex<Generator>);
+
+impl Default for Ids {
+    fn default() -> Self {
+        Self(Mutex::new(Generator::new()))
+    }
+}
+
+impl Ids {
+    pub fn next(&self) -> Ulid {
+        self.0
+            .lock()
+            .expect("id generator lock poisoned")
+            .generate()
+            // only when the random part overflows within one millisecond, which
+            // takes 2^80 ids
+            .unwrap_or_else(|_| Ulid::new())
+    }
+}
+
+impl Debug for Ids {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ids").finish_non_exhaustive()
+    }
+}
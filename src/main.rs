@@ -1,214 +1,4391 @@
+mod archive;
+mod client_ip;
+mod config;
+mod cors;
+mod crypto;
+mod digest;
+mod encoding;
+mod ids;
+mod metrics;
+mod password;
+mod quota;
+mod ratelimit;
+mod sessions;
+mod signing;
+mod storage;
+mod tcp;
+mod tls;
+mod trace;
+mod views;
+mod webhooks;
+
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::net::SocketAddr;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{AddrParseError, SocketAddr};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 
-use axum::body::{Body, to_bytes};
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use axum::body::{Body, Bytes, HttpBody, to_bytes};
+use axum::extract::Request;
 use axum::extract::rejection::LengthLimitError;
-use axum::extract::{Path, State};
-use axum::http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL};
-use axum::http::header::{HeaderName, HeaderValue};
+use axum::extract::{FromRequestParts, MatchedPath, Path, Query, State};
+use axum::handler::Handler;
+use axum::http::header::{
+    ACCEPT, ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ALLOW, AUTHORIZATION, CACHE_CONTROL,
+    CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+    ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION, ORIGIN, RETRY_AFTER, VARY,
+    WWW_AUTHENTICATE,
+};
+use axum::http::header::{HeaderMap, HeaderName, HeaderValue};
+use axum::http::request::Parts;
 use axum::http::status::StatusCode;
-use axum::response::IntoResponse;
-use axum::routing::{get, options, post};
-use axum::{Json, Router};
+use axum::http::{Method, Version};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, options, patch, post};
+use axum::serve::ListenerExt;
+use axum::{Extension, Json, Router};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::TimeDelta;
 use color_eyre::eyre::{self, Context, eyre};
-use libslonk::trace_layer;
+use futures_util::{StreamExt, TryStreamExt, future, stream};
+use mime::Mime;
+use serde::Deserialize;
+use serde::de::IgnoredAny;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use sqlx::types::Uuid;
+use sqlx::types::chrono::{DateTime, Utc};
+use subtle::{Choice, ConstantTimeEq};
 use thiserror::Error;
 use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::select;
-use tokio::signal::unix::Signal;
-use tower::limit::ConcurrencyLimitLayer;
+use tokio::sync::{Notify, Semaphore, SemaphorePermit, watch};
+use tokio::time::{Instant, MissedTickBehavior};
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tracing::field::{Empty, display};
 use tracing::level_filters::LevelFilter;
-use tracing::{error, info};
+use tracing::{Span, debug, error, info, instrument, warn};
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use ulid::Ulid;
 
+use crate::archive::TarGz;
+use crate::client_ip::{Cidr, ProxyTrust};
+use crate::config::Settings;
+use crate::cors::AllowedOrigins;
+use crate::crypto::{Cipher, DecryptionError};
+use crate::digest::{Digests, InvalidDigest};
+use crate::encoding::{Dictionaries, Dictionary, Encoding, negotiate};
+use crate::ids::{IdMode, Ids, is_slug, new_slug};
+use crate::metrics::Metrics;
+use crate::password::InvalidPassword;
+use crate::quota::Quota;
+use crate::ratelimit::{KeyLimit, KeyLimits, RateLimiter, UploadRateLimit};
+use crate::sessions::{Session, Sessions, UPLOAD_OFFSET};
+use crate::signing::Signer;
+use crate::storage::{
+    Backend, Entry, FsStorage, Metadata, NewEntry, PgStorage, Replacement, S3Storage, Staged,
+    Storage, StorageError, hex,
+};
+use crate::tcp::TcpOptions;
+use crate::tls::{Tls, TlsListener};
+use crate::views::Views;
+use crate::webhooks::{Event, Webhooks};
+
+/// default `Cache-Control`, entries never change
 const CACHE_CONTROL_VALUE: HeaderValue = HeaderValue::from_static("max-age=31536000, immutable");
-const CACHE_1Y: (HeaderName, HeaderValue) = (CACHE_CONTROL, CACHE_CONTROL_VALUE);
-const MAX_UPLOAD: usize = 3 * 1024 * 1024;
+/// the default with `ALLOW_PATCH`
+const MUTABLE_CACHE_CONTROL: HeaderValue = HeaderValue::from_static("no-cache");
+/// entries are served compressed depending on `Accept-Encoding`, and as json depending on `Accept`
+const VARY_NEGOTIATED: (HeaderName, HeaderValue) =
+    (VARY, HeaderValue::from_static("accept, accept-encoding"));
+/// keeps uploaded html or svg from running scripts on our origin
+const SANDBOX: (HeaderName, HeaderValue) =
+    (CONTENT_SECURITY_POLICY, HeaderValue::from_static("sandbox"));
+const ALLOW_METHODS: (HeaderName, HeaderValue) = (
+    ACCESS_CONTROL_ALLOW_METHODS,
+    HeaderValue::from_static("GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS"),
+);
+const MAX_AGE: (HeaderName, HeaderValue) =
+    (ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("86400"));
+/// protected entries can't be cached by shared caches, which don't check the password
+const PROTECTED_CACHE_CONTROL: HeaderValue = HeaderValue::from_static("private, no-cache");
+const DEFAULT_CONTENT_TYPE: HeaderValue = HeaderValue::from_static("application/octet-stream");
+const DEFAULT_MAX_UPLOAD: usize = 3 * 1024 * 1024;
+const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+/// how long soft-deleted entries can be restored for, a week
+const DEFAULT_SOFT_DELETE_GRACE: i64 = 7 * 24 * 60 * 60;
+/// seconds the replica is assumed to be behind the primary at most, `REPLICA_LAG`
+const DEFAULT_REPLICA_LAG: i64 = 10;
+/// how often views counted in memory are added to storage
+const VIEWS_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// how much of a streamed upload is buffered before writing it to storage
+const STAGE_CHUNK: usize = 256 * 1024;
+/// how long a resumable upload can go without being appended to before it is dropped
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// how long signed links work for, unless asked otherwise
+const DEFAULT_SIGNED_URL_TTL: u64 = 60 * 60;
+/// how often the storage usage estimate is corrected
+const QUOTA_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
 
-const UPLOAD: &str = "INSERT INTO entries (id, value) VALUES ($1, $2)";
-const RETRIEVE: &str = "SELECT id, value FROM entries WHERE id=$1";
+#[derive(Deserialize, Debug)]
+struct UploadParams {
+    /// seconds until the entry expires
+    ttl: Option<u64>,
+    /// only check the upload, without storing it
+    #[serde(default)]
+    dry_run: bool,
+}
 
-#[derive(sqlx::FromRow, Debug)]
-struct Retrieved {
-    value: Vec<u8>,
+/// what to do with uploads asking for a `ttl` above `MAX_TTL`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TtlOverflow {
+    /// store them with `MAX_TTL` instead
+    Clamp,
+    /// reject them with `400 Bad Request`
+    Reject,
+}
+
+#[derive(Error, Debug)]
+#[error("unknown ttl overflow policy `{0}`")]
+struct UnknownTtlOverflow(String);
+
+impl FromStr for TtlOverflow {
+    type Err = UnknownTtlOverflow;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(Self::Clamp),
+            "reject" => Ok(Self::Reject),
+            _ => Err(UnknownTtlOverflow(s.to_owned())),
+        }
+    }
+}
+
+/// `MAX_UPLOAD` for uploads from specific origins, from `origin=bytes` pairs
+#[derive(Default, Debug)]
+struct OriginLimits(HashMap<String, usize>);
+
+#[derive(Error, Debug)]
+#[error("invalid origin limit `{0}`, expected `origin=bytes`")]
+struct InvalidOriginLimit(String);
+
+impl FromStr for OriginLimits {
+    type Err = InvalidOriginLimit;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                pair.split_once('=')
+                    .and_then(|(origin, max)| Some((origin.trim(), max.trim().parse().ok()?)))
+                    .filter(|(origin, _)| !origin.is_empty())
+                    .map(|(origin, max)| (origin.to_owned(), max))
+                    .ok_or_else(|| InvalidOriginLimit(pair.to_owned()))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// the ttls uploads get and can ask for
+#[derive(Clone, Copy, Debug)]
+struct TtlPolicy {
+    /// for uploads without a `ttl`, which are kept forever if `None`
+    default: Option<u64>,
+    max: Option<u64>,
+    overflow: TtlOverflow,
+}
+
+impl TtlPolicy {
+    /// the ttl an upload asking for `ttl` gets, or why it was rejected
+    #[allow(clippy::result_large_err)]
+    fn apply(&self, ttl: Option<u64>) -> Result<Option<u64>, Response> {
+        match (ttl.or(self.default), self.max) {
+            (Some(ttl), Some(max)) if ttl > max => match self.overflow {
+                TtlOverflow::Clamp => Ok(Some(max)),
+                TtlOverflow::Reject => Err(api_error(
+                    StatusCode::BAD_REQUEST,
+                    "TtlTooLongError",
+                    &format!("`ttl` can be at most {max} seconds"),
+                )),
+            },
+            (ttl, _) => Ok(ttl),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SignatureParams {
+    /// unix timestamp the link stops working at
+    expires: Option<i64>,
+    sig: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DownloadParams {
+    /// anything but `0` or `false` asks for the entry as an attachment
+    download: Option<String>,
+    /// name of the attachment, implies `download`
+    filename: Option<String>,
+}
+
+impl DownloadParams {
+    /// the `Content-Disposition` of the entry at `id`, `None` unless it is downloaded
+    fn disposition(&self, id: Ulid, content_type: Option<&str>) -> Option<HeaderValue> {
+        let download = self
+            .download
+            .as_deref()
+            .is_some_and(|v| v != "0" && v != "false");
+        if !download && self.filename.is_none() {
+            return None;
+        }
+        // anything that would need escaping is dropped, along with path separators
+        let filename: String = self
+            .filename
+            .as_deref()
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || "-_. ".contains(*c))
+            .collect();
+        let filename = match filename.trim() {
+            "" => format!("{id}.{}", extension(content_type)),
+            filename => filename.to_owned(),
+        };
+        HeaderValue::try_from(format!("attachment; filename=\"{filename}\"")).ok()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct TouchParams {
+    /// seconds from now until the entry expires
+    ttl: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SignParams {
+    /// seconds until the link stops working
+    ttl: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListParams {
+    /// id of the last entry on the previous page
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+/// where to listen for connections
+#[derive(Clone, Debug)]
+enum ListenAddr {
+    Tcp(SocketAddr),
+    /// `unix:<path>`
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => s.parse().map(Self::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Error, Debug)]
+#[error("unknown log format `{0}`")]
+struct UnknownLogFormat(String);
+
+impl FromStr for LogFormat {
+    type Err = UnknownLogFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(UnknownLogFormat(s.to_owned())),
+        }
+    }
+}
+
+/// what to do with the database migrations on startup
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum MigrateMode {
+    /// run the ones that haven't been run yet
+    Auto,
+    /// leave them to another instance
+    Skip,
+    /// refuse to start unless all of them have been run
+    Verify,
+}
+
+#[derive(Error, Debug)]
+#[error("unknown migrate mode `{0}`")]
+struct UnknownMigrateMode(String);
+
+impl FromStr for MigrateMode {
+    type Err = UnknownMigrateMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "skip" => Ok(Self::Skip),
+            "verify" => Ok(Self::Verify),
+            _ => Err(UnknownMigrateMode(s.to_owned())),
+        }
+    }
+}
+
+/// the parts of an excalidraw scene checked when `VALIDATE_SCENES` is enabled
+#[derive(Deserialize, Debug)]
+struct Scene {
+    #[serde(rename = "type")]
+    kind: String,
+    #[allow(dead_code)]
+    elements: Vec<IgnoredAny>,
 }
 
 #[derive(Clone, Debug)]
 struct AppState {
-    pub pool: PgPool,
-    pub allow_origin: HeaderValue,
+    pub storage: Arc<dyn Storage>,
+    pub allowed_origins: AllowedOrigins,
+    /// whether cors requests can be made with cookies and other credentials
+    pub cors_credentials: bool,
+    pub cache_control: HeaderValue,
+    pub max_upload: usize,
+    pub origin_limits: Arc<OriginLimits>,
+    /// smallest upload accepted, in bytes uncompressed
+    pub min_upload: usize,
+    /// how long a client has to send the body of an upload
+    pub upload_timeout: Duration,
+    pub ttl: TtlPolicy,
+    /// uploads are rejected once storage is this full, unlimited if `None`
+    pub quota: Option<Arc<Quota>>,
+    pub metrics: Arc<Metrics>,
+    pub compression: Encoding,
+    /// zstd dictionaries, the first of which is used for new entries with zstd compression
+    pub dictionaries: Arc<Dictionaries>,
+    /// keys accepted for uploads, uploads are open to everyone if `None`
+    pub api_keys: Option<Arc<[String]>>,
+    pub upload_limiter: Option<Arc<RateLimiter>>,
+    pub key_limits: Option<Arc<KeyLimits>>,
+    /// whether to take client ips from `X-Forwarded-For`, and from which proxies
+    pub proxy_trust: ProxyTrust,
+    /// content types uploads can have, any if `None`
+    pub allowed_content_types: Option<Arc<[Mime]>>,
+    /// whether to reject uploads that aren't excalidraw scenes
+    pub validate_scenes: bool,
+    /// whether to check values against their checksum before serving them
+    pub verify_on_read: bool,
+    /// encrypts new entries and decrypts encrypted ones
+    pub cipher: Option<Arc<Cipher>>,
+    /// token for the admin endpoints, which are disabled if `None`
+    pub admin_token: Option<Arc<str>>,
+    /// where the api is served, ending in a slash
+    pub base_path: Arc<str>,
+    /// signs links to entries, which are required to retrieve them if `signed_urls_required`
+    pub signer: Option<Signer>,
+    pub signed_urls_required: bool,
+    /// whether deleting only marks entries as deleted, so they can be restored
+    pub soft_delete: bool,
+    /// whether values can be replaced with `PATCH /{id}`, which also turns off deduplication
+    pub allow_patch: bool,
+    /// what new entries are shared as
+    pub id_mode: IdMode,
+    /// whether to give every error a json body, even if the client doesn't ask for json
+    pub json_errors: bool,
+    /// whether writes are rejected, from `MAINTENANCE` and the admin api
+    pub maintenance: Arc<AtomicBool>,
+    /// whether storage was reachable when `watch_storage` last checked
+    pub storage_healthy: Arc<AtomicBool>,
+    /// number of requests being handled
+    pub in_flight: Arc<AtomicUsize>,
+    pub ids: Arc<Ids>,
+    /// whether new values are also stored compressed with gzip, to serve without compressing
+    pub precompute_gzip: bool,
+    /// where retrievals of entries that don't exist are redirected to, instead of a 404
+    pub not_found_redirect: Option<HeaderValue>,
+    /// slots for uploads and retrievals using the database, `DB_INFLIGHT`
+    pub db_inflight: Option<Arc<Semaphore>>,
+    /// whether `/robots.txt` lets crawlers in
+    pub allow_indexing: bool,
+    /// requests taking longer than this are logged as warnings
+    pub slow_request: Option<Duration>,
+    /// views not yet added to storage
+    pub views: Arc<Views>,
+    /// wakes up the task evicting entries beyond `MAX_ENTRIES`, if it is set
+    pub eviction: Option<Arc<Notify>>,
+    /// notified of uploads and deletions, if `WEBHOOK_URL` is set
+    pub webhooks: Option<Webhooks>,
+    /// resumable uploads in progress
+    pub sessions: Arc<Sessions>,
+    /// becomes `true` once a shutdown signal is received
+    pub shutting_down: watch::Receiver<bool>,
 }
 
+// named like the errors they wrap
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 enum InternalError {
     #[error(transparent)]
     AxumError(#[from] axum::Error),
-    #[error("error while contacting database: {0}")]
-    Pgerror(#[from] sqlx::Error),
+    #[error(transparent)]
+    StorageError(#[from] StorageError),
+    #[error("error while encoding metrics: {0}")]
+    MetricsError(#[from] prometheus::Error),
+    #[error("error while compressing entry: {0}")]
+    CompressionError(std::io::Error),
+    #[error("entry is corrupt: failed to decompress: {0}")]
+    DecompressionError(std::io::Error),
+    #[error("entry is compressed with zstd dictionary {0}, which isn't in `ZSTD_DICT`")]
+    MissingDictionaryError(u32),
+    #[error("entry is corrupt or encrypted with another key: {0}")]
+    DecryptionError(#[from] DecryptionError),
+    #[error("entry is corrupt: its value doesn't match its checksum")]
+    ChecksumMismatchError,
+    #[error("entry is encrypted, but `ENCRYPTION_KEY` isn't set")]
+    MissingKeyError,
+    #[error("error while hashing password: {0}")]
+    PasswordHashError(#[from] argon2::password_hash::Error),
+    #[error("no free slug was found in {SLUG_ATTEMPTS} attempts")]
+    SlugExhaustedError,
+}
+
+impl InternalError {
+    /// whether no database connection freed up in time, which is a burst rather than a failure
+    fn is_pool_timeout(&self) -> bool {
+        matches!(
+            self,
+            Self::StorageError(StorageError::Postgres(sqlx::Error::PoolTimedOut))
+        )
+    }
+
+    /// whether the database went away, which the pool recovers from once it is back
+    fn is_unavailable(&self) -> bool {
+        matches!(self, Self::StorageError(err) if err.is_connection_error())
+    }
+
+    fn error_class(&self) -> &'static str {
+        match self {
+            _ if self.is_pool_timeout() => "DatabaseBusyError",
+            _ if self.is_unavailable() => "DatabaseUnavailableError",
+            Self::AxumError(_) => "RequestBodyError",
+            Self::StorageError(_) => "StorageError",
+            Self::MetricsError(_) => "MetricsError",
+            Self::CompressionError(_) => "CompressionError",
+            Self::DecompressionError(_) | Self::MissingDictionaryError(_) => "CorruptEntryError",
+            Self::ChecksumMismatchError => "ChecksumMismatchError",
+            Self::DecryptionError(_) | Self::MissingKeyError => "DecryptionError",
+            Self::PasswordHashError(_) => "PasswordHashError",
+            Self::SlugExhaustedError => "SlugExhaustedError",
+        }
+    }
 }
 
 impl IntoResponse for InternalError {
     fn into_response(self) -> axum::response::Response {
         let error = self.to_string();
+        if self.is_pool_timeout() {
+            warn!("error while handling request: {error}");
+            return (
+                [(RETRY_AFTER, HeaderValue::from(POOL_RETRY_AFTER))],
+                api_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    self.error_class(),
+                    "too many requests at once, try again later",
+                ),
+            )
+                .into_response();
+        }
+        if self.is_unavailable() {
+            warn!("error while handling request: {error}");
+            return (
+                [(RETRY_AFTER, HeaderValue::from(POOL_RETRY_AFTER))],
+                api_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    self.error_class(),
+                    "the database is unavailable, try again later",
+                ),
+            )
+                .into_response();
+        }
         error!("error while handling request: {error}");
-        (StatusCode::INTERNAL_SERVER_ERROR, error).into_response()
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            self.error_class(),
+            &error,
+        )
+    }
+}
+
+/// seconds clients are told to wait when all database connections are busy
+const POOL_RETRY_AFTER: u64 = 1;
+/// how long a request waits for a slot of `DB_INFLIGHT`
+const DB_PERMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// waits a moment for one of the `DB_INFLIGHT` slots, which is held until it is dropped
+async fn db_permit(limit: Option<&Semaphore>) -> Result<Option<SemaphorePermit<'_>>, Response> {
+    let Some(limit) = limit else {
+        return Ok(None);
+    };
+    match tokio::time::timeout(DB_PERMIT_TIMEOUT, limit.acquire()).await {
+        Ok(permit) => Ok(Some(permit.expect("the semaphore is never closed"))),
+        Err(_) => Err((
+            [(RETRY_AFTER, HeaderValue::from(POOL_RETRY_AFTER))],
+            api_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "DatabaseBusyError",
+                "too many requests at once, try again later",
+            ),
+        )
+            .into_response()),
     }
 }
 
+/// an error response in the same shape the excalidraw frontend expects
+fn api_error(status: StatusCode, error_class: &str, message: &str) -> Response {
+    (
+        status,
+        Json(json!({
+            "error_class": error_class,
+            "message": message,
+        })),
+    )
+        .into_response()
+}
+
 async fn handle_options(
-    State(AppState { allow_origin, .. }): State<AppState>,
+    State(AppState { cache_control, .. }): State<AppState>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, InternalError> {
-    Ok([(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin), CACHE_1Y])
+    let mut response = [
+        (CACHE_CONTROL, cache_control),
+        ALLOW_METHODS,
+        MAX_AGE,
+        (
+            VARY,
+            HeaderValue::from_static("access-control-request-headers"),
+        ),
+    ]
+    .into_response();
+    // any header is fine, so allow whatever the client asks for
+    if let Some(request_headers) = headers.get(ACCESS_CONTROL_REQUEST_HEADERS) {
+        response
+            .headers_mut()
+            .insert(ACCESS_CONTROL_ALLOW_HEADERS, request_headers.clone());
+    }
+    Ok(response)
 }
 
-async fn upload(
-    State(AppState { pool, allow_origin }): State<AppState>,
-    body: Body,
-) -> Result<impl IntoResponse, InternalError> {
-    let body = match to_bytes(body, MAX_UPLOAD).await {
-        Ok(v) => v,
-        Err(err) => {
-            if err.source().is_some_and(|e| e.is::<LengthLimitError>()) {
-                return Ok(Json(json!({
-                    "error_class": "RequestTooLargeError"
-                }))
+/// calculates the expiry time of an entry uploaded now, `None` on overflow
+fn expiry(ttl: u64) -> Option<DateTime<Utc>> {
+    let ttl = TimeDelta::try_seconds(ttl.try_into().ok()?)?;
+    Utc::now().checked_add_signed(ttl)
+}
+
+/// parses an id in either its ulid or uuid form, since both name the same 128 bits
+fn parse_id(id: &str) -> Option<Ulid> {
+    Ulid::from_string(id)
+        .ok()
+        .or_else(|| Uuid::try_parse(id).ok().map(Ulid::from))
+}
+
+/// the id of an entry in the request path, rejected with `400 Bad Request` if invalid
+///
+/// with `ID_MODE=short`, slugs are looked up, and rejected with `404 Not Found`
+/// if no entry has them.
+struct EntryId(Ulid);
+
+impl FromRequestParts<AppState> for EntryId {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(id) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if let Some(id) = parse_id(&id) {
+            return Ok(Self(id));
+        }
+        if state.id_mode != IdMode::Short || !is_slug(&id) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Extension(ErrorClass("InvalidIdError", "not a valid ulid or uuid")),
+            )
                 .into_response());
-            } else {
-                return Err(err.into());
-            }
         }
+        match state.storage.resolve_slug(&id).await {
+            Ok(Some(id)) => Ok(Self(id)),
+            Ok(None) => Err(StatusCode::NOT_FOUND.into_response()),
+            Err(err) => Err(internal(err)),
+        }
+    }
+}
+
+/// the `error_class` and message of an error response without a body, used
+/// if the client wants json errors
+#[derive(Clone, Copy, Debug)]
+struct ErrorClass(&'static str, &'static str);
+
+/// gives error responses without a body the usual json body, if `JSON_ERRORS`
+/// is set or the client accepts json
+async fn json_errors(
+    State(AppState { json_errors, .. }): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let json = request.method() != Method::HEAD && (json_errors || wants_json(request.headers()));
+    let response = next.run(request).await;
+    if !json || response.headers().contains_key(CONTENT_TYPE) {
+        return response;
+    }
+    let (class, message) = match (response.status(), response.extensions().get::<ErrorClass>()) {
+        (_, Some(&ErrorClass(class, message))) => (class, message),
+        (StatusCode::NOT_FOUND, None) => ("NotFoundError", "not found"),
+        (StatusCode::BAD_REQUEST, None) => ("BadRequestError", "invalid request"),
+        _ => return response,
     };
 
-    let id = Ulid::new();
-    sqlx::query(UPLOAD)
-        .bind(Uuid::from(id))
-        .bind(&*body)
-        .execute(&pool)
-        .await?;
+    let (mut parts, _) = response.into_parts();
+    let (error, body) = api_error(parts.status, class, message).into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.extend(error.headers);
+    Response::from_parts(parts, body)
+}
 
-    Ok((
-        [(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)],
-        Json(json!({
-            "id": id.to_string(),
-        })),
+/// whether the client asked for entries as json rather than their raw value
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|range| range.split(';').next())
+        .any(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+}
+
+fn accept_encoding(headers: &HeaderMap) -> Option<&str> {
+    headers.get(ACCEPT_ENCODING).and_then(|v| v.to_str().ok())
+}
+
+/// the token in an `Authorization: Bearer <token>` header
+fn bearer_token(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+fn unauthorized() -> Response {
+    (
+        [(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+        api_error(
+            StatusCode::UNAUTHORIZED,
+            "UnauthorizedError",
+            "missing or invalid bearer token",
+        ),
     )
-        .into_response())
+        .into_response()
 }
 
-async fn retrieve(
-    Path(id): Path<String>,
-    State(AppState { pool, allow_origin }): State<AppState>,
-) -> Result<impl IntoResponse, InternalError> {
-    let Ok(id) = Ulid::from_str(&id) else {
-        return Ok(StatusCode::BAD_REQUEST.into_response());
-    };
-    let row = match sqlx::query_as(RETRIEVE)
-        .bind(Uuid::from(id))
-        .fetch_one(&pool)
-        .await
-    {
-        Ok(v) => Ok(Some(v)),
-        Err(err) => match err {
-            sqlx::Error::RowNotFound => Ok(None),
-            _ => Err(err),
-        },
-    }?;
+/// rejects the request with `401 Unauthorized` unless it has a valid
+/// `Authorization: Bearer <key>` header, if api keys are configured
+struct Authorized;
 
-    match row {
-        Some(Retrieved { value, .. }) => Ok((
-            [(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin), CACHE_1Y],
-            value,
-        )
-            .into_response()),
-        None => Ok(StatusCode::NOT_FOUND.into_response()),
+impl FromRequestParts<AppState> for Authorized {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(api_keys) = &state.api_keys else {
+            return Ok(Self);
+        };
+        if let Some(key) = bearer_token(parts) {
+            // compare against every key so the timing doesn't reveal which one matched
+            let valid = api_keys.iter().fold(Choice::from(0), |valid, api_key| {
+                valid | api_key.as_bytes().ct_eq(key.as_bytes())
+            });
+            if valid.into() {
+                return Ok(Self);
+            }
+        }
+        Err(unauthorized())
     }
 }
 
-#[tokio::main]
-async fn main() -> eyre::Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .with(tracing_error::ErrorLayer::default())
-        .init();
-    color_eyre::install()?;
+/// rejects the request with `401 Unauthorized` unless it has an
+/// `Authorization: Bearer <token>` header with the admin token
+struct Admin;
+
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        match (&state.admin_token, bearer_token(parts)) {
+            (Some(admin_token), Some(token))
+                if bool::from(admin_token.as_bytes().ct_eq(token.as_bytes())) =>
+            {
+                Ok(Self)
+            }
+            _ => Err(unauthorized()),
+        }
+    }
+}
 
-    let database_url =
-        std::env::var("DATABASE_URL").map_err(|_| eyre!("`DATABASE_URL` not set"))?;
-    let allow_origin = HeaderValue::from_str(
-        &std::env::var("CORS_ORIGIN")
-            .map(Cow::Owned)
-            .unwrap_or("*".into()),
+/// rejects the request with `503 Service Unavailable` in maintenance mode,
+/// where entries can still be retrieved but not changed
+struct Writable;
+
+impl FromRequestParts<AppState> for Writable {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        _parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if state.maintenance.load(Ordering::Relaxed) {
+            Err(api_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "MaintenanceError",
+                "down for maintenance, entries can be retrieved but not changed",
+            ))
+        } else {
+            Ok(Self)
+        }
+    }
+}
+
+/// rejects the request with `403 Forbidden` unless it has a valid signature for
+/// the entry that hasn't expired, if `SIGNED_URLS_REQUIRED` is set
+struct SignedUrl;
+
+impl FromRequestParts<AppState> for SignedUrl {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(signer) = state.signer.as_ref().filter(|_| state.signed_urls_required) else {
+            return Ok(Self);
+        };
+        let EntryId(id) = EntryId::from_request_parts(parts, state).await?;
+        let Query(SignatureParams { expires, sig }) =
+            Query::<SignatureParams>::from_request_parts(parts, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+        let (Some(expires), Some(sig)) = (expires, sig) else {
+            return Err(signed_url_required());
+        };
+        if !signer.verify(id, expires, &sig) {
+            return Err(api_error(
+                StatusCode::FORBIDDEN,
+                "InvalidSignatureError",
+                "the signature of this link is invalid",
+            ));
+        }
+        if expires <= Utc::now().timestamp() {
+            return Err(api_error(
+                StatusCode::FORBIDDEN,
+                "LinkExpiredError",
+                "this link has expired",
+            ));
+        }
+        Ok(Self)
+    }
+}
+
+fn signed_url_required() -> Response {
+    api_error(
+        StatusCode::FORBIDDEN,
+        "SignatureRequiredError",
+        "entries can only be retrieved with a signed link",
     )
-    .context("failed to parse `CORS_ORIGIN`")?;
-    let socket_addr: SocketAddr = std::env::var("LISTEN")
-        .map(Cow::Owned)
-        .unwrap_or("[::]:2799".into())
-        .parse()
-        .context("failed to parse `LISTEN`")?;
-    let max_concurrency = std::env::var("CONCURRENCY")
-        .map(|v| v.parse().context("failed to parse `CONCURRENCY`"))
-        .ok()
-        .unwrap_or(Ok(100))?;
+}
 
-    let pool = PgPool::connect(&database_url)
-        .await
-        .context("failed to connect to database")?;
+/// the file extension for values of `content_type`, scenes unless it says otherwise
+fn extension(content_type: Option<&str>) -> &'static str {
+    let mime = content_type.and_then(|v| Mime::from_str(v).ok());
+    match mime.as_ref().map(Mime::essence_str) {
+        Some("application/json") => "json",
+        Some("image/png") => "png",
+        Some("image/svg+xml") => "svg",
+        Some("text/plain") => "txt",
+        _ => "excalidraw",
+    }
+}
 
-    sqlx::migrate!("./migrations")
-        .run(&pool)
-        .await
-        .context("failed to run migrations")?;
+/// the `Content-Type` to serve an entry with
+fn content_type(stored: Option<String>) -> HeaderValue {
+    stored
+        .and_then(|v| HeaderValue::try_from(v).ok())
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+}
 
-    let state = AppState { pool, allow_origin };
+/// the id and how it is served are enough to identify a response, plus when
+/// the value was replaced for entries changed with `ALLOW_PATCH`
+fn etag(
+    id: Ulid,
+    modified_at: Option<DateTime<Utc>>,
+    encoding: Encoding,
+    json: bool,
+) -> HeaderValue {
+    let version = match modified_at {
+        Some(modified_at) => format!("{id}.{}", modified_at.timestamp_micros()),
+        None => id.to_string(),
+    };
+    let etag = match encoding {
+        _ if json => format!("\"{version}-json\""),
+        Encoding::Identity => format!("\"{version}\""),
+        _ => format!("\"{version}-{}\"", encoding.as_str()),
+    };
+    HeaderValue::try_from(etag).expect("ulids and encodings are valid header values")
+}
 
-    let app = Router::new()
-        .route("/", post(upload))
-        .route("/{id}", get(retrieve))
-        .fallback(options(handle_options))
-        .layer(trace_layer!())
-        .layer(ConcurrencyLimitLayer::new(max_concurrency))
-        .with_state(state);
+/// the dictionary new entries are compressed with, if they are compressed with zstd
+fn compression_dictionary(state: &AppState) -> Option<&Dictionary> {
+    match state.compression {
+        Encoding::Zstd => state.dictionaries.current(),
+        _ => None,
+    }
+}
 
-    let listener = TcpListener::bind(socket_addr)
-        .await
-        .with_context(|| format!("failed to listen on {socket_addr}"))?;
-    let local_addr = listener.local_addr()?;
-
-    info!("listening on http://{local_addr}");
-
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async {
-            // wanted to have a little bit of fun here :D
-            let ctrl_c = tokio::signal::ctrl_c();
-            let mut sigterm_handler =
-                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate());
-            let sigterm: Pin<Box<dyn Future<Output = Option<()>> + Send>> = sigterm_handler
-                .as_mut()
-                .map(Signal::recv)
-                .map(|fut| Box::pin(fut) as _)
-                .unwrap_or_else(|_| Box::pin(std::future::pending()) as _);
-            select! {
-                _ = sigterm => {},
-                _ = ctrl_c => {}
-            }
-            info!("exiting…");
+/// the stored encoding, unless the value can't be sent as is
+///
+/// clients don't have our dictionaries, so values compressed with one have to
+/// be decompressed first.
+fn servable_encoding(stored: Encoding, dictionary_id: Option<u32>) -> Encoding {
+    match dictionary_id {
+        Some(_) => Encoding::Identity,
+        None => stored,
+    }
+}
+
+/// whether the request has an `If-None-Match` header matching `etag`
+fn etag_matches(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| {
+            tag == "*" || tag.strip_prefix("W/").unwrap_or(tag).as_bytes() == etag.as_bytes()
         })
-        .await
-        .context("failed to serve app")?;
+}
 
-    Ok(())
+/// `Metadata::last_modified` as an http date, for `Last-Modified`
+fn last_modified(modified_at: DateTime<Utc>) -> HeaderValue {
+    HeaderValue::try_from(modified_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+        .expect("dates are valid header values")
+}
+
+/// whether the copy the client has is still fresh
+///
+/// `If-Modified-Since` is only used without `If-None-Match`, like the rfc says.
+fn not_modified(headers: &HeaderMap, etag: &HeaderValue, modified_at: DateTime<Utc>) -> bool {
+    if headers.contains_key(IF_NONE_MATCH) {
+        return etag_matches(headers, etag);
+    }
+    headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        // http dates only have second precision
+        .is_some_and(|since| modified_at.timestamp() <= since.timestamp())
+}
+
+/// responds with `405 Method Not Allowed`, listing the supported methods in `allow`
+fn method_not_allowed(allow: &'static str) -> impl Handler<((),), AppState> {
+    move || async move { (StatusCode::METHOD_NOT_ALLOWED, [(ALLOW, allow)]) }
+}
+
+/// a compressed value, either in memory or already being written to storage
+enum Value {
+    /// with the gzip of the uncompressed value, if `PRECOMPUTE_GZIP` is set
+    Buffered {
+        value: Bytes,
+        gzip: Option<Bytes>,
+    },
+    Staged(Box<dyn Staged>),
+}
+
+impl Value {
+    /// throws away a value that won't be stored
+    async fn discard(self) {
+        let Self::Staged(staged) = self else {
+            return;
+        };
+        if let Err(err) = staged.abort().await {
+            warn!("failed to discard staged value: {err}");
+        }
+    }
+}
+
+/// an upload that passed validation, but isn't stored yet
+struct Upload<'a> {
+    value: Value,
+    /// length of the value as stored, with its precomputed gzip
+    length: u64,
+    /// length of the uncompressed value
+    size: u64,
+    hash: Vec<u8>,
+    content_type: Option<&'a str>,
+    expires_at: Option<DateTime<Utc>>,
+    password_hash: Option<String>,
+}
+
+fn internal(err: impl Into<InternalError>) -> Response {
+    err.into().into_response()
+}
+
+fn too_small(state: &AppState) -> Response {
+    api_error(
+        StatusCode::BAD_REQUEST,
+        "RequestTooSmallError",
+        &format!("uploads must be at least {} bytes", state.min_upload),
+    )
+}
+
+/// the largest upload the `Origin` of a request can make, `MAX_UPLOAD` unless
+/// `ORIGIN_MAX_UPLOAD` says otherwise
+fn max_upload(state: &AppState, headers: &HeaderMap) -> usize {
+    headers
+        .get(ORIGIN)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|origin| state.origin_limits.0.get(origin))
+        .copied()
+        .unwrap_or(state.max_upload)
+}
+
+/// `received_bytes` is the `Content-Length` of the upload, unknown for chunked uploads
+fn too_large(state: &AppState, max_upload: usize, received_bytes: Option<u64>) -> Response {
+    state.metrics.too_large.inc();
+    let mut body = json!({
+        "error_class": "RequestTooLargeError",
+        "message": format!("uploads can be at most {max_upload} bytes"),
+        "max_bytes": max_upload,
+    });
+    if let Some(received_bytes) = received_bytes {
+        body["received_bytes"] = received_bytes.into();
+    }
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response()
+}
+
+fn insufficient_storage(quota: &Quota) -> Response {
+    api_error(
+        StatusCode::INSUFFICIENT_STORAGE,
+        "InsufficientStorageError",
+        &format!("storage quota of {} bytes is used up", quota.max()),
+    )
+}
+
+/// keeps slow clients from holding on to a concurrency slot forever
+fn timed_out(state: &AppState) -> Response {
+    state.metrics.timed_out.inc();
+    api_error(
+        StatusCode::REQUEST_TIMEOUT,
+        "RequestTimeoutError",
+        &format!(
+            "uploads must be received within {}s",
+            state.upload_timeout.as_secs()
+        ),
+    )
+}
+
+/// what an upload is stored with besides its value
+struct UploadOptions<'a> {
+    content_type: Option<&'a str>,
+    expires_at: Option<DateTime<Utc>>,
+    password_hash: Option<String>,
+}
+
+/// the `Content-Type` of an upload, which has to be in `allowed` if that is set
+///
+/// uploads without one count as `application/octet-stream`, which they are served as.
+#[allow(clippy::result_large_err)]
+fn upload_content_type<'a>(
+    allowed: Option<&[Mime]>,
+    headers: &'a HeaderMap,
+) -> Result<Option<&'a str>, Response> {
+    let (content_type, mime) = match headers.get(CONTENT_TYPE).map(HeaderValue::to_str) {
+        Some(Ok(v)) => match v.parse::<Mime>() {
+            Ok(mime) => (Some(v), mime),
+            Err(_) => return Err(StatusCode::BAD_REQUEST.into_response()),
+        },
+        Some(Err(_)) => return Err(StatusCode::BAD_REQUEST.into_response()),
+        None => (None, mime::APPLICATION_OCTET_STREAM),
+    };
+    match allowed {
+        Some(allowed)
+            if !allowed
+                .iter()
+                .any(|a| a.essence_str() == mime.essence_str()) =>
+        {
+            Err(api_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "UnsupportedContentTypeError",
+                &format!("uploads can't be of type {}", mime.essence_str()),
+            ))
+        }
+        _ => Ok(content_type),
+    }
+}
+
+/// validates the headers and query parameters of an upload
+async fn upload_options<'a>(
+    state: &AppState,
+    ttl: Option<u64>,
+    headers: &'a HeaderMap,
+) -> Result<UploadOptions<'a>, Response> {
+    let expires_at = match state.ttl.apply(ttl)?.map(expiry) {
+        Some(Some(v)) => Some(v),
+        Some(None) => return Err(StatusCode::BAD_REQUEST.into_response()),
+        None => None,
+    };
+    let content_type = upload_content_type(state.allowed_content_types.as_deref(), headers)?;
+    let password_hash = match password::from_headers(headers) {
+        Ok(Some(password)) => Some(
+            password::hash(password.to_owned())
+                .await
+                .map_err(internal)?,
+        ),
+        Ok(None) => None,
+        Err(InvalidPassword) => return Err(StatusCode::BAD_REQUEST.into_response()),
+    };
+    Ok(UploadOptions {
+        content_type,
+        expires_at,
+        password_hash,
+    })
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// reads and validates an upload, or responds with why it was rejected
+///
+/// unless scenes have to be validated or it is a dry run, the value is streamed
+/// to storage as it comes in if the backend supports it.
+async fn read_upload<'a>(
+    state: &AppState,
+    ttl: Option<u64>,
+    dry_run: bool,
+    headers: &'a HeaderMap,
+    body: Body,
+) -> Result<Upload<'a>, Response> {
+    let UploadOptions {
+        content_type,
+        expires_at,
+        password_hash,
+    } = upload_options(state, ttl, headers).await?;
+    let encoding = request_encoding(headers)?;
+    let digests = upload_digests(headers)?;
+    let max_upload = max_upload(state, headers);
+
+    let content_length = content_length(headers);
+    // no need to read the body to know it's too large, compressed or not
+    if content_length.is_some_and(|length| length > max_upload as u64) {
+        return Err(too_large(state, max_upload, content_length));
+    }
+    // compressed bodies can be smaller than what they decompress to
+    if encoding == Encoding::Identity
+        && content_length.is_some_and(|length| length < state.min_upload as u64)
+    {
+        return Err(too_small(state));
+    }
+    if let Some(quota) = state.quota.as_deref().filter(|quota| !quota.has_room(0)) {
+        return Err(insufficient_storage(quota));
+    }
+
+    let deadline = Instant::now() + state.upload_timeout;
+    // scenes are validated whole, values are encrypted whole, compressed
+    // bodies are decompressed whole, and gzip is precomputed from the whole value
+    let staged = if dry_run
+        || state.validate_scenes
+        || state.cipher.is_some()
+        || encoding != Encoding::Identity
+        || state.precompute_gzip
+    {
+        None
+    } else {
+        state.storage.stage().await.map_err(internal)?
+    };
+    let (value, length, size, hash) = match staged {
+        Some(mut staged) => {
+            let staged_upload = stream_upload(
+                state,
+                body,
+                max_upload,
+                content_length,
+                deadline,
+                &digests,
+                staged.as_mut(),
+            );
+            match staged_upload.await {
+                Ok((length, size, hash)) => (Value::Staged(staged), length, size, hash),
+                Err(response) => {
+                    Value::Staged(staged).discard().await;
+                    return Err(response);
+                }
+            }
+        }
+        None => {
+            buffer_upload(
+                state,
+                body,
+                encoding,
+                max_upload,
+                content_length,
+                deadline,
+                &digests,
+            )
+            .await?
+        }
+    };
+    if size < state.min_upload as u64 {
+        value.discard().await;
+        return Err(too_small(state));
+    }
+    if let Some(quota) = state
+        .quota
+        .as_deref()
+        .filter(|quota| !quota.has_room(length))
+    {
+        value.discard().await;
+        return Err(insufficient_storage(quota));
+    }
+
+    Ok(Upload {
+        value,
+        length,
+        size,
+        hash,
+        content_type,
+        expires_at,
+        password_hash,
+    })
+}
+
+/// the digests in `Digest` and `Content-MD5`, which the upload has to match
+#[allow(clippy::result_large_err)]
+fn upload_digests(headers: &HeaderMap) -> Result<Digests, Response> {
+    Digests::from_headers(headers).map_err(|InvalidDigest| {
+        api_error(
+            StatusCode::BAD_REQUEST,
+            "InvalidDigestError",
+            "`Digest` and `Content-MD5` must be base64 of a digest",
+        )
+    })
+}
+
+fn digest_mismatch() -> Response {
+    api_error(
+        StatusCode::BAD_REQUEST,
+        "DigestMismatchError",
+        "upload doesn't match the digest it was sent with",
+    )
+}
+
+/// the `Content-Encoding` of an upload, of which only gzip and zstd are supported
+#[allow(clippy::result_large_err)]
+fn request_encoding(headers: &HeaderMap) -> Result<Encoding, Response> {
+    let Some(value) = headers.get(CONTENT_ENCODING) else {
+        return Ok(Encoding::Identity);
+    };
+    match value
+        .to_str()
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Ok("identity") => Ok(Encoding::Identity),
+        Ok("gzip") => Ok(Encoding::Gzip),
+        Ok("zstd") => Ok(Encoding::Zstd),
+        _ => Err(api_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "UnsupportedEncodingError",
+            "uploads can only be compressed with gzip or zstd",
+        )),
+    }
+}
+
+/// reads, decompresses, validates and compresses a whole upload in memory
+///
+/// both the compressed body and what it decompresses to are limited to
+/// `max_upload` bytes, so small bombs can't blow up in memory.
+async fn buffer_upload(
+    state: &AppState,
+    body: Body,
+    encoding: Encoding,
+    max_upload: usize,
+    content_length: Option<u64>,
+    deadline: Instant,
+    digests: &Digests,
+) -> Result<(Value, u64, u64, Vec<u8>), Response> {
+    let body = read_body(
+        state,
+        body,
+        max_upload,
+        max_upload,
+        content_length,
+        deadline,
+    )
+    .await?;
+    let body = if encoding == Encoding::Identity {
+        body
+    } else {
+        match encoding.decompress_limited(&body, max_upload) {
+            Ok(Some(body)) => Bytes::from(body),
+            Ok(None) => return Err(too_large(state, max_upload, None)),
+            Err(_) => {
+                return Err(api_error(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidEncodingError",
+                    &format!("upload is not valid {}", encoding.as_str()),
+                ));
+            }
+        }
+    };
+    let md5 = digests.md5().map(|md5| md5.chain_update(&body));
+    let (value, length, size, hash) = encode_upload(state, body)?;
+    if !digests.matches(&hash, md5) {
+        value.discard().await;
+        return Err(digest_mismatch());
+    }
+    Ok((value, length, size, hash))
+}
+
+/// reads up to `limit` bytes of a body before `deadline`, of an upload that
+/// can be at most `max_upload` bytes
+async fn read_body(
+    state: &AppState,
+    body: Body,
+    limit: usize,
+    max_upload: usize,
+    content_length: Option<u64>,
+    deadline: Instant,
+) -> Result<Bytes, Response> {
+    match tokio::time::timeout_at(deadline, to_bytes(body, limit)).await {
+        Ok(Ok(v)) => Ok(v),
+        Err(_) => Err(timed_out(state)),
+        Ok(Err(err)) => {
+            if err.source().is_some_and(|e| e.is::<LengthLimitError>()) {
+                Err(too_large(state, max_upload, content_length))
+            } else {
+                Err(internal(err))
+            }
+        }
+    }
+}
+
+/// validates, hashes, compresses and encrypts a whole upload, returning the
+/// value, its length, its uncompressed size and its hash
+#[allow(clippy::result_large_err)]
+fn encode_upload(state: &AppState, body: Bytes) -> Result<(Value, u64, u64, Vec<u8>), Response> {
+    if state.validate_scenes
+        && !serde_json::from_slice::<Scene>(&body).is_ok_and(|scene| scene.kind == "excalidraw")
+    {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "InvalidSceneError",
+            "upload is not an excalidraw scene",
+        ));
+    }
+
+    let hash = Sha256::digest(&body).to_vec();
+    let compressed = match compression_dictionary(state) {
+        Some(dictionary) => dictionary.compress(&body).map(Cow::Owned),
+        None => state.compression.compress(&body),
+    };
+    let value = match compressed.map_err(|err| internal(InternalError::CompressionError(err)))? {
+        Cow::Borrowed(_) => body.clone(),
+        Cow::Owned(value) => Bytes::from(value),
+    };
+    // a value stored with gzip already is its own gzip
+    let gzip = if state.precompute_gzip && state.compression != Encoding::Gzip {
+        let gzip = Encoding::Gzip
+            .compress(&body)
+            .map_err(|err| internal(InternalError::CompressionError(err)))?;
+        Some(Bytes::from(gzip.into_owned()))
+    } else {
+        None
+    };
+    let (value, gzip) = match &state.cipher {
+        Some(cipher) => (
+            Bytes::from(cipher.encrypt(&value)),
+            gzip.map(|gzip| Bytes::from(cipher.encrypt(&gzip))),
+        ),
+        None => (value, gzip),
+    };
+    let length = (value.len() + gzip.as_ref().map_or(0, Bytes::len)) as u64;
+    Ok((
+        Value::Buffered { value, gzip },
+        length,
+        body.len() as u64,
+        hash,
+    ))
+}
+
+/// compresses an upload as it comes in and writes it to `staged`, returning
+/// the length written, its size and its hash
+async fn stream_upload(
+    state: &AppState,
+    body: Body,
+    max_upload: usize,
+    content_length: Option<u64>,
+    deadline: Instant,
+    digests: &Digests,
+    staged: &mut dyn Staged,
+) -> Result<(u64, u64, Vec<u8>), Response> {
+    let mut compressor = match compression_dictionary(state) {
+        Some(dictionary) => dictionary.compressor(),
+        None => state.compression.compressor(),
+    }
+    .map_err(|err| internal(InternalError::CompressionError(err)))?;
+    let mut hasher = Sha256::new();
+    let mut md5 = digests.md5();
+    let mut size = 0;
+    let mut length = 0;
+    let mut pending = Vec::new();
+
+    let mut stream = body.into_data_stream();
+    loop {
+        let chunk = match tokio::time::timeout_at(deadline, stream.next()).await {
+            Ok(Some(chunk)) => chunk.map_err(internal)?,
+            Ok(None) => break,
+            Err(_) => return Err(timed_out(state)),
+        };
+        size += chunk.len();
+        if size > max_upload {
+            return Err(too_large(state, max_upload, content_length));
+        }
+        hasher.update(&chunk);
+        if let Some(md5) = &mut md5 {
+            md5.update(&chunk);
+        }
+        compressor
+            .write(&chunk)
+            .map_err(|err| internal(InternalError::CompressionError(err)))?;
+        pending.append(&mut compressor.take_output());
+        // avoid a round trip to storage for every tiny chunk
+        if pending.len() >= STAGE_CHUNK {
+            staged.write(&pending).await.map_err(internal)?;
+            length += pending.len();
+            pending.clear();
+        }
+    }
+    pending.append(
+        &mut compressor
+            .finish()
+            .map_err(|err| internal(InternalError::CompressionError(err)))?,
+    );
+    if !pending.is_empty() {
+        staged.write(&pending).await.map_err(internal)?;
+        length += pending.len();
+    }
+
+    let hash = hasher.finalize().to_vec();
+    if !digests.matches(&hash, md5) {
+        return Err(digest_mismatch());
+    }
+    Ok((length as u64, size as u64, hash))
+}
+
+/// stores an upload, returning `false` if `id` is taken
+async fn store(state: &AppState, id: Ulid, upload: Upload<'_>) -> Result<bool, InternalError> {
+    let entry = NewEntry {
+        encoding: state.compression,
+        dictionary_id: compression_dictionary(state).map(Dictionary::id),
+        // nothing is staged when encrypting, so every value is encrypted then
+        encrypted: state.cipher.is_some(),
+        password_hash: upload.password_hash.as_deref(),
+        size: upload.size,
+        content_type: upload.content_type,
+        hash: &upload.hash,
+        expires_at: upload.expires_at,
+        created_at: Utc::now(),
+        gzip: None,
+    };
+    let stored = match upload.value {
+        Value::Buffered { value, gzip } => {
+            let entry = NewEntry {
+                gzip: gzip.as_deref(),
+                ..entry
+            };
+            state.storage.put(id, &value, entry).await?
+        }
+        Value::Staged(staged) => staged.commit(id, entry).await?,
+    };
+
+    if stored {
+        if let Some(quota) = &state.quota {
+            quota.add(upload.length);
+        }
+        state.metrics.uploads.inc();
+        state.metrics.upload_size.observe(upload.size as f64);
+        if let Some(webhooks) = &state.webhooks {
+            webhooks.send(Event::upload(id, upload.size));
+        }
+        if let Some(eviction) = &state.eviction {
+            eviction.notify_one();
+        }
+    }
+    Ok(stored)
+}
+
+#[instrument(skip_all, fields(id = Empty, size = Empty))]
+#[allow(clippy::too_many_arguments)]
+async fn upload(
+    _: Writable,
+    _: Authorized,
+    _: UploadRateLimit,
+    _key_limit: KeyLimit,
+    State(state): State<AppState>,
+    Query(UploadParams { ttl, dry_run }): Query<UploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, InternalError> {
+    let upload = match read_upload(&state, ttl, dry_run, &headers, body).await {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    Span::current().record("size", upload.size);
+    if dry_run {
+        return Ok(Json(json!({ "valid": true })).into_response());
+    }
+    // only taken once the body is in, slow clients shouldn't hold it up
+    let _permit = match db_permit(state.db_inflight.as_deref()).await {
+        Ok(v) => v,
+        Err(response) => {
+            upload.value.discard().await;
+            return Ok(response);
+        }
+    };
+    let id = save(&state, upload).await?;
+    Span::current().record("id", display(id));
+
+    Ok(Json(json!({
+        "id": shared_id(&state, id).await?,
+    }))
+    .into_response())
+}
+
+/// slugs tried before giving up, they only collide once a good part of them is taken
+const SLUG_ATTEMPTS: u32 = 5;
+
+/// what an entry is shared as, its ulid or, with `ID_MODE=short`, its slug,
+/// which it is given if it doesn't have one yet
+async fn shared_id(state: &AppState, id: Ulid) -> Result<String, InternalError> {
+    if state.id_mode == IdMode::Ulid {
+        return Ok(id.to_string());
+    }
+    for _ in 0..SLUG_ATTEMPTS {
+        if let Some(slug) = state.storage.assign_slug(id, &new_slug()).await? {
+            return Ok(slug);
+        }
+    }
+    Err(InternalError::SlugExhaustedError)
+}
+
+/// stores an upload at a new id, unless there already is an entry with the same value
+async fn save(state: &AppState, upload: Upload<'_>) -> Result<Ulid, InternalError> {
+    // protected entries would be unlocked by the password of whoever uploaded
+    // them first, and replacing an entry would change every duplicate of it
+    let duplicate = if upload.password_hash.is_some() || state.allow_patch {
+        Ok(None)
+    } else {
+        state
+            .storage
+            .find_duplicate(&upload.hash, upload.content_type, upload.expires_at)
+            .await
+    };
+    let id = match duplicate {
+        Ok(Some(id)) => {
+            state.metrics.uploads.inc();
+            upload.value.discard().await;
+            id
+        }
+        Ok(None) => {
+            let id = state.ids.next();
+            // a new ulid is only ever taken if a retried insert already went through
+            store(state, id, upload).await?;
+            id
+        }
+        Err(err) => {
+            upload.value.discard().await;
+            return Err(err.into());
+        }
+    };
+    Ok(id)
+}
+
+/// starts a resumable upload, taking the same headers and query parameters as `upload`
+async fn create_session(
+    _: Writable,
+    _: Authorized,
+    _: UploadRateLimit,
+    _key_limit: KeyLimit,
+    State(state): State<AppState>,
+    Query(UploadParams { ttl, .. }): Query<UploadParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, InternalError> {
+    let options = match upload_options(&state, ttl, &headers).await {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    if let Some(quota) = state.quota.as_deref().filter(|quota| !quota.has_room(0)) {
+        return Ok(insufficient_storage(quota));
+    }
+
+    let session = Session {
+        content_type: options.content_type.map(str::to_owned),
+        expires_at: options.expires_at,
+        password_hash: options.password_hash,
+        received: Vec::new(),
+        updated_at: Instant::now(),
+    };
+    let Some(id) = state.sessions.create(session) else {
+        return Ok(api_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "TooManySessionsError",
+            "too many resumable uploads in progress",
+        ));
+    };
+
+    Ok((
+        StatusCode::CREATED,
+        [(UPLOAD_OFFSET, HeaderValue::from_static("0"))],
+        Json(json!({
+            "id": id.to_string(),
+        })),
+    )
+        .into_response())
+}
+
+fn session_not_found() -> Response {
+    api_error(
+        StatusCode::NOT_FOUND,
+        "SessionNotFoundError",
+        "no such upload in progress",
+    )
+}
+
+fn session_busy() -> Response {
+    api_error(
+        StatusCode::CONFLICT,
+        "ConflictError",
+        "another request is using this upload",
+    )
+}
+
+/// how much of a resumable upload was received, which is where the client should resume
+async fn session_offset(
+    _: Authorized,
+    EntryId(id): EntryId,
+    State(AppState { sessions, .. }): State<AppState>,
+) -> Response {
+    let Some(session) = sessions.get(id) else {
+        return session_not_found();
+    };
+    // waits for requests still appending to it
+    let session = session.lock().await;
+    (
+        StatusCode::NO_CONTENT,
+        [
+            (CACHE_CONTROL, HeaderValue::from_static("no-store")),
+            (UPLOAD_OFFSET, HeaderValue::from(session.received.len())),
+        ],
+    )
+        .into_response()
+}
+
+/// appends the body to a resumable upload, at the offset in `Upload-Offset`
+async fn append_session(
+    _: Writable,
+    _: Authorized,
+    EntryId(id): EntryId,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let Some(session) = state.sessions.get(id) else {
+        return session_not_found();
+    };
+    let Ok(mut session) = session.try_lock_owned() else {
+        return session_busy();
+    };
+    // completed while we were getting the lock
+    if !state.sessions.contains(id) {
+        return session_not_found();
+    }
+
+    let offset = session.received.len();
+    let Some(client_offset) = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    // the client lost track, appending anyway would corrupt the upload
+    if client_offset != offset {
+        return (
+            [(UPLOAD_OFFSET, HeaderValue::from(offset))],
+            api_error(
+                StatusCode::CONFLICT,
+                "OffsetMismatchError",
+                &format!("upload is at offset {offset}, not {client_offset}"),
+            ),
+        )
+            .into_response();
+    }
+
+    let max_upload = max_upload(&state, &headers);
+    let remaining = max_upload.saturating_sub(offset);
+    let content_length = content_length(&headers).map(|length| offset as u64 + length);
+    if content_length.is_some_and(|length| length > max_upload as u64) {
+        return too_large(&state, max_upload, content_length);
+    }
+    let deadline = Instant::now() + state.upload_timeout;
+    let read = read_body(
+        &state,
+        body,
+        remaining,
+        max_upload,
+        content_length,
+        deadline,
+    );
+    let chunk = match read.await {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    session.received.extend_from_slice(&chunk);
+    session.updated_at = Instant::now();
+
+    (
+        StatusCode::NO_CONTENT,
+        [(UPLOAD_OFFSET, HeaderValue::from(session.received.len()))],
+    )
+        .into_response()
+}
+
+/// stores a resumable upload like `upload` would have
+async fn complete_session(
+    _: Writable,
+    _: Authorized,
+    EntryId(id): EntryId,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, InternalError> {
+    let Some(session) = state.sessions.get(id) else {
+        return Ok(session_not_found());
+    };
+    let Ok(mut session) = session.try_lock_owned() else {
+        return Ok(session_busy());
+    };
+    // whoever removes the session gets to store it
+    if !state.sessions.remove(id) {
+        return Ok(session_not_found());
+    }
+    let Session {
+        content_type,
+        expires_at,
+        password_hash,
+        received,
+        ..
+    } = &mut *session;
+
+    let (value, length, size, hash) = match encode_upload(&state, mem::take(received).into()) {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    if size < state.min_upload as u64 {
+        value.discard().await;
+        return Ok(too_small(&state));
+    }
+    if let Some(quota) = state
+        .quota
+        .as_deref()
+        .filter(|quota| !quota.has_room(length))
+    {
+        value.discard().await;
+        return Ok(insufficient_storage(quota));
+    }
+    let upload = Upload {
+        value,
+        length,
+        size,
+        hash,
+        content_type: content_type.as_deref(),
+        expires_at: *expires_at,
+        password_hash: password_hash.take(),
+    };
+    let id = save(&state, upload).await?;
+
+    Ok(Json(json!({
+        "id": shared_id(&state, id).await?,
+    }))
+    .into_response())
+}
+
+/// like `upload`, but at an id chosen by the client
+///
+/// with `If-None-Match: *`, a taken id is `412 Precondition Failed` instead of
+/// `409 Conflict`, so clients can tell a retried create apart from a conflict.
+#[allow(clippy::too_many_arguments)]
+async fn create(
+    _: Writable,
+    _: Authorized,
+    _: UploadRateLimit,
+    _key_limit: KeyLimit,
+    EntryId(id): EntryId,
+    State(state): State<AppState>,
+    Query(UploadParams { ttl, .. }): Query<UploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, InternalError> {
+    let if_absent = headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .any(|v| v.as_bytes().trim_ascii() == b"*");
+    let taken = || {
+        let (status, class) = if if_absent {
+            (StatusCode::PRECONDITION_FAILED, "PreconditionFailedError")
+        } else {
+            (StatusCode::CONFLICT, "ConflictError")
+        };
+        api_error(status, class, "an entry with this id already exists")
+    };
+    // no need to read the body if it won't be stored anyway
+    if if_absent && state.storage.metadata(id).await?.is_some() {
+        return Ok(taken());
+    }
+    let upload = match read_upload(&state, ttl, false, &headers, body).await {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    let _permit = match db_permit(state.db_inflight.as_deref()).await {
+        Ok(v) => v,
+        Err(response) => {
+            upload.value.discard().await;
+            return Ok(response);
+        }
+    };
+
+    if !store(&state, id, upload).await? {
+        return Ok(taken());
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        [(ETAG, etag(id, None, Encoding::Identity, false))],
+        Json(json!({
+            "id": id.to_string(),
+        })),
+    )
+        .into_response())
+}
+
+/// replaces the value of an existing entry, keeping its password, expiry and
+/// creation time, and its content type unless a new one is given
+///
+/// only routed with `ALLOW_PATCH`.
+#[allow(clippy::too_many_arguments)]
+async fn replace(
+    _: Writable,
+    _: Authorized,
+    _: UploadRateLimit,
+    _key_limit: KeyLimit,
+    EntryId(id): EntryId,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, InternalError> {
+    let Some(metadata) = state.storage.metadata(id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    // whoever can't read the entry can't overwrite it either
+    if let Err(response) = check_password(&headers, metadata.password_hash.as_deref()).await {
+        return Ok(response);
+    }
+    let content_type = match upload_content_type(state.allowed_content_types.as_deref(), &headers) {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    let encoding = match request_encoding(&headers) {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    let digests = match upload_digests(&headers) {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+
+    let max_upload = max_upload(&state, &headers);
+    let content_length = content_length(&headers);
+    if content_length.is_some_and(|length| length > max_upload as u64) {
+        return Ok(too_large(&state, max_upload, content_length));
+    }
+    // compressed bodies can be smaller than what they decompress to
+    if encoding == Encoding::Identity
+        && content_length.is_some_and(|length| length < state.min_upload as u64)
+    {
+        return Ok(too_small(&state));
+    }
+    let deadline = Instant::now() + state.upload_timeout;
+    // replacing a value in place isn't something staging can do
+    let buffered = buffer_upload(
+        &state,
+        body,
+        encoding,
+        max_upload,
+        content_length,
+        deadline,
+        &digests,
+    );
+    let (value, length, size, hash) = match buffered.await {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    let Value::Buffered { value, gzip } = value else {
+        unreachable!("buffered uploads are never staged");
+    };
+    if size < state.min_upload as u64 {
+        return Ok(too_small(&state));
+    }
+    // only what the value grows by takes up more room
+    if let Some(quota) = state
+        .quota
+        .as_deref()
+        .filter(|quota| !quota.has_room(length.saturating_sub(metadata.length)))
+    {
+        return Ok(insufficient_storage(quota));
+    }
+
+    let replacement = Replacement {
+        encoding: state.compression,
+        dictionary_id: compression_dictionary(&state).map(Dictionary::id),
+        encrypted: state.cipher.is_some(),
+        size,
+        content_type,
+        hash: &hash,
+        modified_at: Utc::now(),
+        gzip: gzip.as_deref(),
+    };
+    if !state.storage.replace(id, &value, replacement).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    if let Some(quota) = &state.quota {
+        quota.replace(metadata.length, length);
+    }
+    state.metrics.uploads.inc();
+    state.metrics.upload_size.observe(size as f64);
+
+    Ok(Json(json!({
+        "id": id.to_string(),
+    }))
+    .into_response())
+}
+
+/// responds with `401 Unauthorized` unless the request has the password of a protected entry
+async fn check_password(headers: &HeaderMap, password_hash: Option<&str>) -> Result<(), Response> {
+    let Some(password_hash) = password_hash else {
+        return Ok(());
+    };
+    let password = match password::from_headers(headers) {
+        Ok(Some(password)) => password,
+        Ok(None) => {
+            return Err(api_error(
+                StatusCode::UNAUTHORIZED,
+                "PasswordRequiredError",
+                "entry is protected by a password",
+            ));
+        }
+        Err(InvalidPassword) => return Err(StatusCode::BAD_REQUEST.into_response()),
+    };
+    if password::verify(password_hash.to_owned(), password.to_owned())
+        .await
+        .map_err(internal)?
+    {
+        Ok(())
+    } else {
+        Err(api_error(
+            StatusCode::UNAUTHORIZED,
+            "InvalidPasswordError",
+            "wrong password",
+        ))
+    }
+}
+
+/// the `Cache-Control` to serve an entry with
+fn entry_cache_control(cache_control: HeaderValue, password_hash: Option<&str>) -> HeaderValue {
+    match password_hash {
+        Some(_) => PROTECTED_CACHE_CONTROL,
+        None => cache_control,
+    }
+}
+
+/// decrypts a stored value and recompresses it with `target`
+fn transcode(
+    cipher: Option<&Cipher>,
+    dictionaries: &Dictionaries,
+    mut value: Vec<u8>,
+    stored: Encoding,
+    dictionary_id: Option<u32>,
+    encrypted: bool,
+    target: Encoding,
+) -> Result<Vec<u8>, InternalError> {
+    if encrypted {
+        let cipher = cipher.ok_or(InternalError::MissingKeyError)?;
+        value = cipher.decrypt(&value)?;
+    }
+    // never equal if the value was compressed with a dictionary
+    if target != stored {
+        value = match dictionary_id {
+            Some(dictionary_id) => dictionaries
+                .get(dictionary_id)
+                .ok_or(InternalError::MissingDictionaryError(dictionary_id))?
+                .decompress(value),
+            None => stored.decompress(value),
+        }
+        .map_err(InternalError::DecompressionError)?;
+        if target != Encoding::Identity {
+            value = target
+                .compress(&value)
+                .map_err(InternalError::CompressionError)?
+                .into_owned();
+        }
+    }
+    Ok(value)
+}
+
+/// like `transcode`, checking the uncompressed value against `hash` on the way
+#[allow(clippy::too_many_arguments)]
+fn transcode_verified(
+    cipher: Option<&Cipher>,
+    dictionaries: &Dictionaries,
+    value: Vec<u8>,
+    stored: Encoding,
+    dictionary_id: Option<u32>,
+    encrypted: bool,
+    target: Encoding,
+    hash: &str,
+) -> Result<Vec<u8>, InternalError> {
+    let value = transcode(
+        cipher,
+        dictionaries,
+        value,
+        stored,
+        dictionary_id,
+        encrypted,
+        Encoding::Identity,
+    )?;
+    if hex(&Sha256::digest(&value)) != hash {
+        return Err(InternalError::ChecksumMismatchError);
+    }
+    match target {
+        Encoding::Identity => Ok(value),
+        _ => Ok(target
+            .compress(&value)
+            .map_err(InternalError::CompressionError)?
+            .into_owned()),
+    }
+}
+
+#[instrument(skip_all, fields(%id, size = Empty))]
+async fn retrieve(
+    EntryId(id): EntryId,
+    _: SignedUrl,
+    State(AppState {
+        storage,
+        cache_control,
+        metrics,
+        views,
+        cipher,
+        dictionaries,
+        verify_on_read,
+        compression,
+        precompute_gzip,
+        not_found_redirect,
+        db_inflight,
+        ..
+    }): State<AppState>,
+    Query(download): Query<DownloadParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, InternalError> {
+    let _permit = match db_permit(db_inflight.as_deref()).await {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    let accept_encoding = accept_encoding(&headers);
+    // json is never compressed, its value is base64 of the uncompressed value
+    let json = wants_json(&headers);
+
+    // avoid loading the value if the client already has it
+    let conditional =
+        headers.contains_key(IF_NONE_MATCH) || headers.contains_key(IF_MODIFIED_SINCE);
+    let metadata = if conditional {
+        storage.metadata(id).await?
+    } else {
+        None
+    };
+    if let Some(metadata) = metadata {
+        let password_hash = metadata.password_hash.as_deref();
+        let target = if json {
+            Encoding::Identity
+        } else {
+            negotiate(
+                accept_encoding,
+                servable_encoding(metadata.encoding, metadata.dictionary_id),
+            )
+        };
+        let etag = etag(id, metadata.modified_at, target, json);
+        if not_modified(&headers, &etag, metadata.last_modified()) {
+            if let Err(response) = check_password(&headers, password_hash).await {
+                return Ok(response);
+            }
+            return Ok((
+                StatusCode::NOT_MODIFIED,
+                [
+                    (
+                        CACHE_CONTROL,
+                        entry_cache_control(cache_control, password_hash),
+                    ),
+                    VARY_NEGOTIATED,
+                    (ETAG, etag),
+                    (LAST_MODIFIED, last_modified(metadata.last_modified())),
+                ],
+            )
+                .into_response());
+        }
+    }
+
+    // the precomputed gzip is only worth loading if it would be compressed to gzip anyway
+    let entry =
+        if precompute_gzip && !json && negotiate(accept_encoding, compression) == Encoding::Gzip {
+            storage
+                .get_gzip(id)
+                .await?
+                .map(|Entry { value, metadata }| {
+                    (metadata, stream::once(future::ready(Ok(value))).boxed())
+                })
+        } else {
+            storage.get_stream(id).await?
+        };
+    match entry {
+        Some((
+            Metadata {
+                encoding: stored,
+                dictionary_id,
+                encrypted,
+                length,
+                content_type: stored_content_type,
+                password_hash,
+                hash,
+                created_at,
+                modified_at,
+                ..
+            },
+            value,
+        )) => {
+            Span::current().record("size", length);
+            if let Err(response) = check_password(&headers, password_hash.as_deref()).await {
+                return Ok(response);
+            }
+            let cache_control = entry_cache_control(cache_control, password_hash.as_deref());
+            let disposition = download.disposition(id, stored_content_type.as_deref());
+            let target = if json {
+                Encoding::Identity
+            } else {
+                negotiate(accept_encoding, servable_encoding(stored, dictionary_id))
+            };
+
+            metrics.retrieval_hits.inc();
+            views.record(id);
+            let entry_headers = [
+                (CACHE_CONTROL, cache_control),
+                VARY_NEGOTIATED,
+                (ETAG, etag(id, modified_at, target, json)),
+                (
+                    LAST_MODIFIED,
+                    last_modified(modified_at.unwrap_or(created_at)),
+                ),
+            ];
+            let verify = verify_on_read && hash.is_some();
+            // values sent as they are stored never have to be in memory all at once
+            if target == stored && !encrypted && !json && !verify {
+                let mut response = (
+                    entry_headers,
+                    [
+                        (CONTENT_TYPE, content_type(stored_content_type)),
+                        SANDBOX,
+                        (CONTENT_LENGTH, HeaderValue::from(length)),
+                    ],
+                    Body::from_stream(value),
+                )
+                    .into_response();
+                if target != Encoding::Identity {
+                    response
+                        .headers_mut()
+                        .insert(CONTENT_ENCODING, HeaderValue::from_static(target.as_str()));
+                }
+                if let Some(disposition) = disposition {
+                    response
+                        .headers_mut()
+                        .insert(CONTENT_DISPOSITION, disposition);
+                }
+                return Ok(response);
+            }
+
+            let value = value
+                .try_fold(Vec::with_capacity(length as usize), |mut value, chunk| {
+                    value.extend_from_slice(&chunk);
+                    future::ready(Ok(value))
+                })
+                .await?;
+            let value = match hash.filter(|_| verify_on_read) {
+                Some(hash) => transcode_verified(
+                    cipher.as_deref(),
+                    &dictionaries,
+                    value,
+                    stored,
+                    dictionary_id,
+                    encrypted,
+                    target,
+                    &hash,
+                )?,
+                None => transcode(
+                    cipher.as_deref(),
+                    &dictionaries,
+                    value,
+                    stored,
+                    dictionary_id,
+                    encrypted,
+                    target,
+                )?,
+            };
+            if json {
+                let body = json!({ "id": id.to_string(), "value": STANDARD.encode(value) });
+                return Ok((entry_headers, Json(body)).into_response());
+            }
+            let mut response = (
+                entry_headers,
+                [(CONTENT_TYPE, content_type(stored_content_type)), SANDBOX],
+                value,
+            )
+                .into_response();
+            if target != Encoding::Identity {
+                response
+                    .headers_mut()
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static(target.as_str()));
+            }
+            if let Some(disposition) = disposition {
+                response
+                    .headers_mut()
+                    .insert(CONTENT_DISPOSITION, disposition);
+            }
+            Ok(response)
+        }
+        None => {
+            metrics.retrieval_misses.inc();
+            // api clients asking for json want to know the entry is gone
+            match not_found_redirect.filter(|_| !json) {
+                Some(location) => Ok((StatusCode::FOUND, [(LOCATION, location)]).into_response()),
+                None => Ok(StatusCode::NOT_FOUND.into_response()),
+            }
+        }
+    }
+}
+
+/// most ids `POST /batch` accepts at once
+const MAX_BATCH: usize = 100;
+
+/// retrieves several entries at once, as base64
+///
+/// ids that are invalid or name entries that don't exist or have a password map to `null`.
+async fn batch(
+    State(AppState {
+        storage,
+        metrics,
+        views,
+        cipher,
+        dictionaries,
+        verify_on_read,
+        signed_urls_required,
+        db_inflight,
+        ..
+    }): State<AppState>,
+    Json(ids): Json<Vec<String>>,
+) -> Result<Response, InternalError> {
+    // a link can only be signed for one entry
+    if signed_urls_required {
+        return Ok(signed_url_required());
+    }
+    if ids.len() > MAX_BATCH {
+        return Ok(api_error(
+            StatusCode::BAD_REQUEST,
+            "BatchTooLargeError",
+            &format!("at most {MAX_BATCH} ids can be retrieved at once"),
+        ));
+    }
+    let _permit = match db_permit(db_inflight.as_deref()).await {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    let parsed: Vec<_> = ids.iter().filter_map(|id| parse_id(id)).collect();
+    let entries: HashMap<_, _> = storage.get_many(&parsed).await?.into_iter().collect();
+
+    let mut values = serde_json::Map::with_capacity(ids.len());
+    for raw in ids {
+        if values.contains_key(&raw) {
+            continue;
+        }
+        let entry = parse_id(&raw)
+            .and_then(|id| entries.get(&id).map(|entry| (id, entry)))
+            .filter(|(_, entry)| entry.metadata.password_hash.is_none());
+        let Some((id, Entry { value, metadata })) = entry else {
+            metrics.retrieval_misses.inc();
+            values.insert(raw, serde_json::Value::Null);
+            continue;
+        };
+        let value = transcode(
+            cipher.as_deref(),
+            &dictionaries,
+            value.clone(),
+            metadata.encoding,
+            metadata.dictionary_id,
+            metadata.encrypted,
+            Encoding::Identity,
+        )?;
+        let expected = metadata.hash.as_deref().filter(|_| verify_on_read);
+        if expected.is_some_and(|hash| hex(&Sha256::digest(&value)) != hash) {
+            return Err(InternalError::ChecksumMismatchError);
+        }
+        metrics.retrieval_hits.inc();
+        views.record(id);
+        values.insert(raw, STANDARD.encode(value).into());
+    }
+    Ok(Json(values).into_response())
+}
+
+async fn head(
+    EntryId(id): EntryId,
+    _: SignedUrl,
+    State(AppState {
+        storage,
+        cache_control,
+        db_inflight,
+        ..
+    }): State<AppState>,
+    Query(download): Query<DownloadParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, InternalError> {
+    let _permit = match db_permit(db_inflight.as_deref()).await {
+        Ok(v) => v,
+        Err(response) => return Ok(response),
+    };
+    let Some(Metadata {
+        encoding: stored,
+        dictionary_id,
+        encrypted,
+        length,
+        size,
+        content_type: stored_content_type,
+        password_hash,
+        created_at,
+        modified_at,
+        ..
+    }) = storage.metadata(id).await?
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if let Err(response) = check_password(&headers, password_hash.as_deref()).await {
+        return Ok(response);
+    }
+    let cache_control = entry_cache_control(cache_control, password_hash.as_deref());
+
+    // same negotiation as `retrieve`, so the headers match
+    let json = wants_json(&headers);
+    let target = if json {
+        Encoding::Identity
+    } else {
+        negotiate(
+            accept_encoding(&headers),
+            servable_encoding(stored, dictionary_id),
+        )
+    };
+    let etag = etag(id, modified_at, target, json);
+    let modified_at = modified_at.unwrap_or(created_at);
+    let status = if not_modified(&headers, &etag, modified_at) {
+        StatusCode::NOT_MODIFIED
+    } else {
+        StatusCode::OK
+    };
+
+    let mut response = (
+        status,
+        [
+            (CACHE_CONTROL, cache_control),
+            VARY_NEGOTIATED,
+            (ETAG, etag),
+            (LAST_MODIFIED, last_modified(modified_at)),
+        ],
+    )
+        .into_response();
+    if status == StatusCode::OK && json {
+        let headers = response.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static(mime::APPLICATION_JSON.as_ref()),
+        );
+    } else if status == StatusCode::OK {
+        let disposition = download.disposition(id, stored_content_type.as_deref());
+        let headers = response.headers_mut();
+        if let Some(disposition) = disposition {
+            headers.insert(CONTENT_DISPOSITION, disposition);
+        }
+        headers.insert(CONTENT_TYPE, content_type(stored_content_type));
+        headers.insert(SANDBOX.0, SANDBOX.1);
+        if target != Encoding::Identity {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(target.as_str()));
+        }
+        // unknown if the value would have to be compressed on the fly
+        let content_length = if target == stored {
+            // the nonce and tag are removed when decrypting
+            Some(if encrypted {
+                length.saturating_sub(crypto::OVERHEAD)
+            } else {
+                length
+            })
+        } else if target == Encoding::Identity {
+            size
+        } else {
+            None
+        };
+        if let Some(content_length) = content_length {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(content_length));
+        }
+    }
+    Ok(response)
+}
+
+/// whether there is an entry at `id`, as json, without looking at its value
+async fn exists(
+    EntryId(id): EntryId,
+    _: SignedUrl,
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<impl IntoResponse, InternalError> {
+    Ok(Json(json!({ "exists": storage.exists(id).await? })))
+}
+
+async fn meta(
+    EntryId(id): EntryId,
+    _: SignedUrl,
+    State(AppState { storage, .. }): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, InternalError> {
+    let Some(Metadata {
+        size,
+        hash,
+        created_at,
+        views,
+        password_hash,
+        ..
+    }) = storage.metadata(id).await?
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if let Err(response) = check_password(&headers, password_hash.as_deref()).await {
+        return Ok(response);
+    }
+
+    Ok(Json(json!({
+        "id": id.to_string(),
+        "size": size,
+        "sha256": hash,
+        "created_at": created_at,
+        "views": views,
+    }))
+    .into_response())
+}
+
+/// pushes back when an entry expires, to `ttl` seconds from now or
+/// `DEFAULT_TTL`, so entries in use don't expire
+///
+/// entries that never expire are left alone, and expiries are never moved earlier.
+async fn touch(
+    _: Writable,
+    _: Authorized,
+    EntryId(id): EntryId,
+    State(AppState { storage, ttl, .. }): State<AppState>,
+    Query(TouchParams { ttl: requested }): Query<TouchParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, InternalError> {
+    let Some(Metadata {
+        expires_at,
+        password_hash,
+        ..
+    }) = storage.metadata(id).await?
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    // whoever can't read the entry can't keep it around either
+    if let Err(response) = check_password(&headers, password_hash.as_deref()).await {
+        return Ok(response);
+    }
+    let extended = match ttl.apply(requested) {
+        Ok(Some(ttl)) => match expiry(ttl) {
+            Some(v) => Some(v),
+            None => return Ok(StatusCode::BAD_REQUEST.into_response()),
+        },
+        Ok(None) => None,
+        Err(response) => return Ok(response),
+    };
+
+    let expires_at = match (expires_at, extended) {
+        (Some(current), Some(extended)) if extended > current => {
+            if !storage.extend_expiry(id, extended).await? {
+                return Ok(StatusCode::NOT_FOUND.into_response());
+            }
+            Some(extended)
+        }
+        (expires_at, _) => expires_at,
+    };
+
+    Ok(Json(json!({
+        "id": id.to_string(),
+        "expires_at": expires_at,
+    }))
+    .into_response())
+}
+
+async fn delete(
+    _: Writable,
+    _: Authorized,
+    EntryId(id): EntryId,
+    State(AppState {
+        storage,
+        soft_delete,
+        webhooks,
+        ..
+    }): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, InternalError> {
+    let Some(metadata) = storage.metadata(id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    // whoever can't read the entry can't delete it either
+    if let Err(response) = check_password(&headers, metadata.password_hash.as_deref()).await {
+        return Ok(response);
+    }
+    let deleted = if soft_delete {
+        storage.soft_delete(id).await?
+    } else {
+        storage.delete(id).await?
+    };
+    if let Some(webhooks) = webhooks.filter(|_| deleted) {
+        webhooks.send(Event::delete(id));
+    }
+    let status = if deleted {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    };
+
+    Ok(status.into_response())
+}
+
+/// undeletes a soft-deleted entry
+async fn restore(
+    _: Writable,
+    _: Admin,
+    EntryId(id): EntryId,
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<impl IntoResponse, InternalError> {
+    let status = if storage.restore(id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    };
+
+    Ok(status.into_response())
+}
+
+#[derive(Deserialize, Debug)]
+struct MaintenanceBody {
+    enabled: bool,
+}
+
+/// whether the server is in maintenance mode
+async fn get_maintenance(
+    _: Admin,
+    State(AppState { maintenance, .. }): State<AppState>,
+) -> impl IntoResponse {
+    Json(json!({ "enabled": maintenance.load(Ordering::Relaxed) }))
+}
+
+/// turns maintenance mode on or off, until the next restart
+async fn set_maintenance(
+    _: Admin,
+    State(AppState { maintenance, .. }): State<AppState>,
+    Json(MaintenanceBody { enabled }): Json<MaintenanceBody>,
+) -> impl IntoResponse {
+    if maintenance.swap(enabled, Ordering::Relaxed) != enabled {
+        info!(
+            "maintenance mode turned {} through the admin api",
+            if enabled { "on" } else { "off" }
+        );
+    }
+    Json(json!({ "enabled": enabled }))
+}
+
+/// creates a signed link to an entry, which works until `ttl` seconds from now
+async fn sign(
+    _: Admin,
+    EntryId(id): EntryId,
+    State(AppState {
+        storage,
+        base_path,
+        signer,
+        ..
+    }): State<AppState>,
+    Query(SignParams { ttl }): Query<SignParams>,
+) -> Result<impl IntoResponse, InternalError> {
+    let signer = signer.expect("only routed with a signing key");
+    let Some(expires_at) = expiry(ttl.unwrap_or(DEFAULT_SIGNED_URL_TTL)) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    if storage.metadata(id).await?.is_none() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let expires = expires_at.timestamp();
+    let sig = signer.sign(id, expires);
+
+    Ok(Json(json!({
+        "url": format!("{base_path}{id}?expires={expires}&sig={sig}"),
+        "expires_at": expires_at,
+    }))
+    .into_response())
+}
+
+async fn list_entries(
+    _: Admin,
+    State(AppState { storage, .. }): State<AppState>,
+    Query(ListParams { after, limit }): Query<ListParams>,
+) -> Result<impl IntoResponse, InternalError> {
+    let after = match after.as_deref().map(parse_id) {
+        Some(Some(v)) => Some(v),
+        Some(None) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+        None => None,
+    };
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let entries = storage.list(after, limit).await?;
+    let next = entries
+        .last()
+        .filter(|_| entries.len() == limit)
+        .map(|(id, _)| id.to_string());
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(id, metadata)| {
+            json!({
+                "id": id.to_string(),
+                "size": metadata.size,
+                "created_at": metadata.created_at,
+                "views": metadata.views,
+                "protected": metadata.password_hash.is_some(),
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "entries": entries,
+        "next": next,
+    }))
+    .into_response())
+}
+
+/// entries read from storage at once while exporting
+const EXPORT_PAGE_SIZE: usize = 100;
+
+/// one line of `/admin/export`, and of `/admin/import`
+#[derive(Deserialize, Debug)]
+struct ExportedEntry {
+    id: String,
+    /// base64 of the uncompressed value
+    value: String,
+    content_type: Option<String>,
+    password_hash: Option<String>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// one page of entries as exported lines, and the id to continue after
+async fn export_page(
+    state: &AppState,
+    after: Option<Ulid>,
+) -> Result<(Bytes, Option<Ulid>), InternalError> {
+    let page = state.storage.list(after, EXPORT_PAGE_SIZE).await?;
+    let next = page
+        .last()
+        .filter(|_| page.len() == EXPORT_PAGE_SIZE)
+        .map(|&(id, _)| id);
+    let ids: Vec<_> = page.into_iter().map(|(id, _)| id).collect();
+    let mut lines = Vec::new();
+    // entries deleted since they were listed are left out
+    for (id, Entry { value, metadata }) in state.storage.get_many(&ids).await? {
+        let value = transcode(
+            state.cipher.as_deref(),
+            &state.dictionaries,
+            value,
+            metadata.encoding,
+            metadata.dictionary_id,
+            metadata.encrypted,
+            Encoding::Identity,
+        )?;
+        let line = json!({
+            "id": id.to_string(),
+            "value": STANDARD.encode(value),
+            "content_type": metadata.content_type,
+            "password_hash": metadata.password_hash,
+            "created_at": metadata.created_at,
+            "expires_at": metadata.expires_at,
+        });
+        serde_json::to_writer(&mut lines, &line).expect("json values are always serializable");
+        lines.push(b'\n');
+    }
+    Ok((Bytes::from(lines), next))
+}
+
+/// streams every entry as newline-delimited json, a page at a time
+///
+/// values are decrypted and decompressed, so they can be imported anywhere.
+async fn export(_: Admin, State(state): State<AppState>) -> impl IntoResponse {
+    let pages = stream::unfold(Some(None), move |after| {
+        let state = state.clone();
+        async move {
+            let after = after?;
+            match export_page(&state, after).await {
+                Ok((lines, next)) => Some((Ok(lines), next.map(Some))),
+                Err(err) => {
+                    // too late for an error response, the client sees a cut off body
+                    error!("export failed: {err}");
+                    Some((Err(err), None))
+                }
+            }
+        }
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(pages),
+    )
+}
+
+/// most ids `POST /admin/archive` accepts at once
+const MAX_ARCHIVE: usize = 1000;
+
+/// an archive being streamed, with the ids still to be read
+struct Archive {
+    state: AppState,
+    ids: Vec<String>,
+    seen: HashSet<Ulid>,
+    tar: TarGz,
+    included: Vec<String>,
+    skipped: Vec<serde_json::Value>,
+}
+
+/// adds the next few entries to an archive, returning what of it is compressed so far
+async fn archive_page(archive: &mut Archive) -> Result<Vec<u8>, InternalError> {
+    let count = archive.ids.len().min(EXPORT_PAGE_SIZE);
+    let raw: Vec<_> = archive.ids.drain(..count).collect();
+    let parsed: Vec<_> = raw.iter().filter_map(|id| parse_id(id)).collect();
+    let mut entries: HashMap<_, _> = archive
+        .state
+        .storage
+        .get_many(&parsed)
+        .await?
+        .into_iter()
+        .collect();
+
+    let mut output = Vec::new();
+    for raw in raw {
+        let Some(id) = parse_id(&raw) else {
+            archive
+                .skipped
+                .push(json!({ "id": raw, "reason": "invalid_id" }));
+            continue;
+        };
+        // an entry is only archived once, however many times it is asked for
+        if !archive.seen.insert(id) {
+            continue;
+        }
+        let Some(Entry { value, metadata }) = entries.remove(&id) else {
+            archive
+                .skipped
+                .push(json!({ "id": raw, "reason": "not_found" }));
+            continue;
+        };
+        let value = transcode(
+            archive.state.cipher.as_deref(),
+            &archive.state.dictionaries,
+            value,
+            metadata.encoding,
+            metadata.dictionary_id,
+            metadata.encrypted,
+            Encoding::Identity,
+        )?;
+        let mtime = metadata.last_modified().timestamp().max(0) as u64;
+        let name = id.to_string();
+        output.extend(
+            archive
+                .tar
+                .append(&name, mtime, &value)
+                .map_err(InternalError::CompressionError)?,
+        );
+        archive.included.push(name);
+    }
+    Ok(output)
+}
+
+/// ends an archive with `manifest.json`, listing what is in it and what was skipped
+fn finish_archive(archive: Archive) -> Result<Vec<u8>, InternalError> {
+    let Archive {
+        mut tar,
+        included,
+        skipped,
+        ..
+    } = archive;
+    let manifest = serde_json::to_vec_pretty(&json!({
+        "included": included,
+        "skipped": skipped,
+    }))
+    .expect("json values are always serializable");
+    let now = Utc::now().timestamp().max(0) as u64;
+    let mut output = tar
+        .append("manifest.json", now, &manifest)
+        .map_err(InternalError::CompressionError)?;
+    output.extend(tar.finish().map_err(InternalError::CompressionError)?);
+    Ok(output)
+}
+
+/// streams the entries with the given ids as a gzipped tarball, one file per
+/// entry named by its id, decrypted and decompressed
+async fn archive(
+    _: Admin,
+    State(state): State<AppState>,
+    Json(ids): Json<Vec<String>>,
+) -> Result<Response, InternalError> {
+    if ids.len() > MAX_ARCHIVE {
+        return Ok(api_error(
+            StatusCode::BAD_REQUEST,
+            "ArchiveTooLargeError",
+            &format!("at most {MAX_ARCHIVE} ids can be archived at once"),
+        ));
+    }
+    let archive = Archive {
+        state,
+        ids,
+        seen: HashSet::new(),
+        tar: TarGz::new().map_err(InternalError::CompressionError)?,
+        included: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let chunks = stream::unfold(Some(archive), |archive| async move {
+        let mut archive = archive?;
+        let (result, archive) = if archive.ids.is_empty() {
+            (finish_archive(archive), None)
+        } else {
+            let result = archive_page(&mut archive).await;
+            (result, Some(archive))
+        };
+        match result {
+            Ok(chunk) => Some((Ok(Bytes::from(chunk)), archive)),
+            Err(err) => {
+                // too late for an error response, the client sees a cut off body
+                error!("archive failed: {err}");
+                Some((Err(err), None))
+            }
+        }
+    });
+    Ok((
+        [
+            (CONTENT_TYPE, "application/gzip"),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"archive.tar.gz\"",
+            ),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response())
+}
+
+fn invalid_import(index: usize, imported: u64, message: &str) -> Response {
+    api_error(
+        StatusCode::BAD_REQUEST,
+        "InvalidImportError",
+        &format!("entry {index}: {message}, {imported} entries were imported before it"),
+    )
+}
+
+/// stores the `index`th exported entry, counting from 1, returning `false` if
+/// its id is taken or it expired
+async fn import_line(
+    state: &AppState,
+    index: usize,
+    imported: u64,
+    line: &[u8],
+) -> Result<bool, Response> {
+    let invalid = |message: &str| invalid_import(index, imported, message);
+    let entry: ExportedEntry =
+        serde_json::from_slice(line).map_err(|err| invalid(&err.to_string()))?;
+    let id = parse_id(&entry.id).ok_or_else(|| invalid("invalid id"))?;
+    let body = STANDARD
+        .decode(&entry.value)
+        .map_err(|_| invalid("value is not valid base64"))?;
+    if body.len() > state.max_upload {
+        return Err(invalid("value is larger than `MAX_UPLOAD`"));
+    }
+    if entry
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Ok(false);
+    }
+
+    let (value, length, size, hash) = encode_upload(state, Bytes::from(body))?;
+    if let Some(quota) = state
+        .quota
+        .as_deref()
+        .filter(|quota| !quota.has_room(length))
+    {
+        return Err(insufficient_storage(quota));
+    }
+    let Value::Buffered { value, gzip } = value else {
+        unreachable!("`encode_upload` never stages");
+    };
+    let new_entry = NewEntry {
+        encoding: state.compression,
+        dictionary_id: compression_dictionary(state).map(Dictionary::id),
+        encrypted: state.cipher.is_some(),
+        password_hash: entry.password_hash.as_deref(),
+        size,
+        content_type: entry.content_type.as_deref(),
+        hash: &hash,
+        expires_at: entry.expires_at,
+        created_at: entry.created_at,
+        gzip: gzip.as_deref(),
+    };
+    let stored = state
+        .storage
+        .put(id, &value, new_entry)
+        .await
+        .map_err(internal)?;
+    if let Some(quota) = state.quota.as_ref().filter(|_| stored) {
+        quota.add(length);
+    }
+    Ok(stored)
+}
+
+/// stores entries in the format of `/admin/export`, a line at a time
+///
+/// entries whose id is taken are skipped, so an import that was cut off can
+/// just be retried.
+async fn import(
+    _: Writable,
+    _: Admin,
+    State(state): State<AppState>,
+    body: Body,
+) -> Result<Response, InternalError> {
+    // base64 grows values by a third, and the rest of the line is small
+    let max_line = state.max_upload.div_ceil(3) * 4 + 16 * 1024;
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut index = 0;
+    let mut pending = Vec::new();
+    let mut stream = body.into_data_stream();
+    loop {
+        let chunk = stream.next().await.transpose()?;
+        let done = chunk.is_none();
+        match chunk {
+            Some(chunk) => pending.extend_from_slice(&chunk),
+            // the last line doesn't need a newline
+            None if !pending.is_empty() => pending.push(b'\n'),
+            None => {}
+        }
+        let mut start = 0;
+        while let Some(end) = pending[start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|end| start + end)
+        {
+            let line = pending[start..end].trim_ascii();
+            start = end + 1;
+            if line.is_empty() {
+                continue;
+            }
+            index += 1;
+            match import_line(&state, index, imported, line).await {
+                Ok(true) => imported += 1,
+                Ok(false) => skipped += 1,
+                Err(response) => return Ok(response),
+            }
+        }
+        pending.drain(..start);
+        if pending.len() > max_line {
+            return Ok(invalid_import(index + 1, imported, "line is too long"));
+        }
+        if done {
+            break;
+        }
+    }
+
+    if let Some(eviction) = state.eviction.as_ref().filter(|_| imported > 0) {
+        eviction.notify_one();
+    }
+    Ok(Json(json!({
+        "imported": imported,
+        "skipped": skipped,
+    }))
+    .into_response())
+}
+
+async fn stats(
+    _: Admin,
+    State(AppState { storage, .. }): State<AppState>,
+) -> Result<impl IntoResponse, InternalError> {
+    let stats = storage.stats().await?;
+    Ok(Json(json!({
+        "count": stats.count,
+        "total_bytes": stats.total_length,
+        "average_bytes": stats.average_length,
+        "median_bytes": stats.median_length,
+        "oldest": stats.oldest,
+        "newest": stats.newest,
+    })))
+}
+
+async fn health(State(AppState { storage, .. }): State<AppState>) -> impl IntoResponse {
+    check_storage(&*storage).await
+}
+
+async fn check_storage(storage: &dyn Storage) -> (StatusCode, Json<serde_json::Value>) {
+    match storage.health().await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        Err(err) => {
+            warn!("health check failed: {err}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "status": "degraded" })),
+            )
+        }
+    }
+}
+
+/// whether the process is running, regardless of storage
+async fn livez() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+/// whether requests should be sent here, which stops being the case as soon as
+/// shutting down starts, so load balancers drain traffic before the server stops
+async fn readyz(
+    State(AppState {
+        storage_healthy,
+        shutting_down,
+        maintenance,
+        ..
+    }): State<AppState>,
+) -> impl IntoResponse {
+    if *shutting_down.borrow() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "status": "shutting_down" })),
+        );
+    }
+    // storage is expected to be flaky during maintenance, which is no reason
+    // to stop sending reads here
+    if maintenance.load(Ordering::Relaxed) {
+        return (StatusCode::OK, Json(json!({ "status": "maintenance" })));
+    }
+    if !storage_healthy.load(Ordering::Relaxed) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "status": "degraded" })),
+        );
+    }
+    (StatusCode::OK, Json(json!({ "status": "ok" })))
+}
+
+/// which build is running, as captured by build.rs
+async fn version() -> impl IntoResponse {
+    let built_at = env!("BUILD_TIMESTAMP")
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0));
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": option_env!("GIT_COMMIT"),
+        "built_at": built_at,
+    }))
+}
+
+/// what this service is and what uploads it takes, for anyone who opens it in a browser
+async fn info(State(state): State<AppState>) -> impl IntoResponse {
+    Json(json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "max_upload_bytes": state.max_upload,
+        "max_ttl": state.ttl.max,
+        "auth_required": state.api_keys.is_some(),
+    }))
+}
+
+/// keeps crawlers away from entries, which only make sense to whoever has the link
+const ROBOTS_DISALLOW: &str = "User-agent: *\nDisallow: /\n";
+const ROBOTS_ALLOW: &str = "User-agent: *\nAllow: /\n";
+
+async fn robots(State(AppState { allow_indexing, .. }): State<AppState>) -> impl IntoResponse {
+    (
+        [
+            (CONTENT_TYPE, HeaderValue::from_static("text/plain")),
+            (
+                CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=86400"),
+            ),
+        ],
+        if allow_indexing {
+            ROBOTS_ALLOW
+        } else {
+            ROBOTS_DISALLOW
+        },
+    )
+}
+
+/// hand-written, keep it in sync with the handlers and API.md
+const OPENAPI: &str = include_str!("openapi.json");
+
+async fn openapi() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "application/json")], OPENAPI)
+}
+
+async fn export_metrics(
+    State(AppState { metrics, .. }): State<AppState>,
+) -> Result<impl IntoResponse, InternalError> {
+    Ok(([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics.encode()?))
+}
+
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// how long a request waits for its turn under `CONCURRENCY`
+const OVERLOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// holds requests past `CONCURRENCY` back for a moment, then turns them away
+/// with a `Retry-After` instead of letting them pile up
+async fn limit_concurrency(
+    State(limit): State<Arc<Semaphore>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Ok(permit) = tokio::time::timeout(OVERLOAD_TIMEOUT, limit.acquire()).await else {
+        return (
+            [(RETRY_AFTER, HeaderValue::from(POOL_RETRY_AFTER))],
+            api_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "OverloadedError",
+                "too many requests at once, try again later",
+            ),
+        )
+            .into_response();
+    };
+    let _permit = permit.expect("the semaphore is never closed");
+    next.run(request).await
+}
+
+/// when `hold_back` can send a response, set by `add_jitter`
+#[derive(Clone, Copy, Debug)]
+struct JitterDeadline(Instant);
+
+/// has responses held back until a random moment between half of
+/// `GET_JITTER_MS` and all of it after the request came in, so retrievals that
+/// take less than that don't tell existing ids apart from missing ones by how
+/// long they take
+async fn add_jitter(State(max): State<Option<Duration>>, request: Request, next: Next) -> Response {
+    let Some(max) = max else {
+        return next.run(request).await;
+    };
+    let spread = (max - max / 2).as_millis() as u64 + 1;
+    let deadline = Instant::now() + max / 2 + Duration::from_millis(OsRng.next_u64() % spread);
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(JitterDeadline(deadline));
+    response
+}
+
+/// waits for the `JitterDeadline` of a response before sending it, outside of
+/// `CONCURRENCY` so waiting responses don't take up its slots
+async fn hold_back(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    if let Some(&JitterDeadline(deadline)) = response.extensions().get() {
+        tokio::time::sleep_until(deadline).await;
+    }
+    response
+}
+
+/// counts requests being handled, so shutdown can report how many it cut off
+async fn track_in_flight(
+    State(AppState { in_flight, .. }): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    in_flight.fetch_add(1, Ordering::Relaxed);
+    let _guard = InFlightGuard(in_flight);
+    next.run(request).await
+}
+
+/// records the duration and response size of every request, and warns about slow ones
+async fn observe_request(
+    State(AppState {
+        metrics,
+        slow_request,
+        ..
+    }): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    // the route rather than the uri, so ids don't each get their own metric
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => "fallback".to_string(),
+    };
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+    let size = response.body().size_hint().exact();
+    metrics.observe_request(method.as_str(), &route, elapsed, size);
+    if slow_request.is_some_and(|slow| elapsed >= slow) {
+        warn!(
+            status = response.status().as_u16(),
+            size,
+            "slow request to {route}, took {}ms",
+            elapsed.as_millis()
+        );
+    }
+    response
+}
+
+/// how often `watch_storage` checks on storage
+const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// checks storage in the background, so `/readyz` follows outages without
+/// querying storage itself, and the pool reconnects before requests need it
+async fn watch_storage(storage: Arc<dyn Storage>, healthy: Arc<AtomicBool>) {
+    let mut interval = tokio::time::interval(STORAGE_CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let result = storage.health().await;
+        let was_healthy = healthy.swap(result.is_ok(), Ordering::Relaxed);
+        match result {
+            Ok(_) if !was_healthy => info!("storage is reachable again"),
+            Err(err) if was_healthy => warn!("storage is unreachable: {err}"),
+            _ => {}
+        }
+    }
+}
+
+/// periodically deletes expired entries, and soft-deleted ones once `grace` has passed
+async fn sweep(storage: Arc<dyn Storage>, period: Duration, grace: TimeDelta) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let deleted_before = Utc::now()
+            .checked_sub_signed(grace)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        match storage.sweep(deleted_before).await {
+            Ok(swept) if swept > 0 => info!("swept {swept} expired or deleted entries"),
+            Ok(_) => {}
+            Err(err) => error!("failed to sweep entries: {err}"),
+        }
+    }
+}
+
+/// evicts entries beyond `max` whenever woken up, which happens after every new upload
+///
+/// wakeups while evicting are coalesced into one more round.
+async fn evict(storage: Arc<dyn Storage>, max: u64, wakeup: Arc<Notify>) {
+    loop {
+        wakeup.notified().await;
+        match storage.evict(max).await {
+            Ok(evicted) if evicted > 0 => {
+                info!("evicted {evicted} least recently used entries to stay under `MAX_ENTRIES`")
+            }
+            Ok(_) => {}
+            Err(err) => error!("failed to evict entries: {err}"),
+        }
+    }
+}
+
+/// waits for SIGTERM or SIGINT
+#[cfg(unix)]
+async fn stop_requested() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    // a signal we can't listen for just never arrives
+    let recv = |kind| async move {
+        match signal(kind) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!("failed to listen for {kind:?}: {err}");
+                std::future::pending().await
+            }
+        }
+    };
+    select! {
+        _ = recv(SignalKind::terminate()) => {},
+        _ = recv(SignalKind::interrupt()) => {}
+    }
+}
+
+/// waits for ctrl-c or ctrl-break
+#[cfg(windows)]
+async fn stop_requested() {
+    use tokio::signal::windows::{ctrl_break, ctrl_c};
+
+    let ctrl_c = async {
+        match ctrl_c() {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!("failed to listen for ctrl-c: {err}");
+                std::future::pending().await
+            }
+        }
+    };
+    let ctrl_break = async {
+        match ctrl_break() {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!("failed to listen for ctrl-break: {err}");
+                std::future::pending().await
+            }
+        }
+    };
+    select! {
+        _ = ctrl_c => {},
+        _ = ctrl_break => {}
+    }
+}
+
+/// waits for ctrl-c
+#[cfg(not(any(unix, windows)))]
+async fn stop_requested() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        warn!("failed to listen for ctrl-c: {err}");
+        std::future::pending().await
+    }
+}
+
+/// fails readiness once asked to stop, then waits `pre_stop_delay` for load
+/// balancers to notice before the servers stop taking requests
+async fn shutdown_signal(
+    shutting_down: watch::Sender<bool>,
+    draining: watch::Sender<bool>,
+    pre_stop_delay: Duration,
+) {
+    stop_requested().await;
+    shutting_down.send_replace(true);
+    if !pre_stop_delay.is_zero() {
+        info!(
+            "shutting down in {}s, until then only failing readiness",
+            pre_stop_delay.as_secs()
+        );
+        tokio::time::sleep(pre_stop_delay).await;
+    }
+    info!("exiting…");
+    draining.send_replace(true);
+}
+
+/// rejects http/2 without tls, which is only served with `HTTP2_CLEARTEXT`
+async fn reject_h2c(request: Request, next: Next) -> Response {
+    if request.version() == Version::HTTP_2 {
+        return StatusCode::HTTP_VERSION_NOT_SUPPORTED.into_response();
+    }
+    next.run(request).await
+}
+
+/// binds to `addr`, returning the server running on it
+///
+/// http/2 is negotiated with alpn over tls, and otherwise spoken by clients
+/// with prior knowledge if `http2_cleartext` is set.
+async fn serve(
+    addr: &ListenAddr,
+    app: Router,
+    tls: Option<Arc<Tls>>,
+    tcp: TcpOptions,
+    http2_cleartext: bool,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> eyre::Result<Pin<Box<dyn Future<Output = io::Result<()>> + Send>>> {
+    // hyper takes http/2 prior knowledge on any connection, so it is turned away here
+    let app = if tls.is_none() && !http2_cleartext {
+        app.layer(middleware::from_fn(reject_h2c))
+    } else {
+        app
+    };
+    Ok(match addr {
+        ListenAddr::Tcp(socket_addr) => {
+            let listener = TcpListener::bind(socket_addr)
+                .await
+                .with_context(|| format!("failed to listen on {socket_addr}"))?;
+            let local_addr = listener.local_addr()?;
+            let service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+            match tls {
+                Some(tls) => {
+                    info!("listening on https://{local_addr}");
+                    // `ConnectInfo<SocketAddr>` is only available for axum's own and for
+                    // tapped listeners, the orphan rule keeps us from adding ours
+                    let listener = TlsListener::new(listener, tls, tcp)?.tap_io(|_| {});
+                    Box::pin(
+                        axum::serve(listener, service)
+                            .with_graceful_shutdown(shutdown)
+                            .into_future(),
+                    )
+                }
+                None => {
+                    info!("listening on http://{local_addr}");
+                    let listener = listener.tap_io(move |stream| tcp.apply(stream));
+                    Box::pin(
+                        axum::serve(listener, service)
+                            .with_graceful_shutdown(shutdown)
+                            .into_future(),
+                    )
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => return Err(eyre!("unix sockets are only supported on unix")),
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            // left behind if the last run didn't exit cleanly
+            remove_socket(path)
+                .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("failed to listen on {}", path.display()))?;
+
+            info!("listening on unix:{}", path.display());
+
+            // there are no client ips, so rate limiting needs `TRUST_PROXY`
+            let service = app.into_make_service();
+            Box::pin(
+                axum::serve(listener, service)
+                    .with_graceful_shutdown(shutdown)
+                    .into_future(),
+            )
+        }
+    })
+}
+
+/// removes the socket at `path`, if there is one
+#[cfg(unix)]
+fn remove_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(not(unix))]
+fn remove_socket(_path: &std::path::Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// checks that every migration was run, and not changed since
+async fn verify_migrations(pool: &PgPool, migrator: &Migrator) -> eyre::Result<()> {
+    let mut conn = pool
+        .acquire()
+        .await
+        .context("failed to connect to database")?;
+    let applied = conn
+        .list_applied_migrations()
+        .await
+        .context("failed to check migrations")?;
+    for migration in migrator.iter() {
+        if migration.migration_type.is_down_migration() {
+            continue;
+        }
+        match applied.iter().find(|a| a.version == migration.version) {
+            Some(a) if a.checksum == migration.checksum => {}
+            Some(_) => {
+                return Err(eyre!(
+                    "migration {} ({}) was changed after it was run",
+                    migration.version,
+                    migration.description
+                ));
+            }
+            None => {
+                return Err(eyre!(
+                    "migration {} ({}) hasn't been run, run it or start with `MIGRATE=auto`",
+                    migration.version,
+                    migration.description
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the libpq variables `database_options` falls back to
+const PG_VARS: [&str; 5] = ["PGHOST", "PGPORT", "PGUSER", "PGPASSWORD", "PGDATABASE"];
+
+/// connection options from `DATABASE_URL`, or else from the `PG*` variables like libpq
+fn database_options(settings: &Settings) -> eyre::Result<PgConnectOptions> {
+    if let Some(url) = settings.var("DATABASE_URL") {
+        return PgConnectOptions::from_str(&url).context("failed to parse `DATABASE_URL`");
+    }
+    if !PG_VARS.iter().any(|name| settings.var(name).is_some()) {
+        return Err(eyre!("`DATABASE_URL` not set"));
+    }
+    // also picks up the other libpq variables, like `PGSSLMODE`, from the environment
+    let mut options = PgConnectOptions::new();
+    if let Some(host) = settings.var("PGHOST") {
+        options = options.host(&host);
+    }
+    if let Some(port) = settings.parse("PGPORT")? {
+        options = options.port(port);
+    }
+    if let Some(user) = settings.var("PGUSER") {
+        options = options.username(&user);
+    }
+    if let Some(password) = settings.var("PGPASSWORD") {
+        options = options.password(&password);
+    }
+    if let Some(database) = settings.var("PGDATABASE") {
+        options = options.database(&database);
+    }
+    Ok(options)
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let settings = Settings::load()?;
+    let log_format = settings.parse("LOG_FORMAT")?.unwrap_or(LogFormat::Text);
+    tracing_subscriber::registry()
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .with((log_format == LogFormat::Text).then(tracing_subscriber::fmt::layer))
+        // one object per line, with the fields of the request span
+        .with((log_format == LogFormat::Json).then(|| tracing_subscriber::fmt::layer().json()))
+        .with(tracing_error::ErrorLayer::default())
+        .init();
+    color_eyre::install()?;
+
+    let backend = settings
+        .parse("STORAGE_BACKEND")?
+        .unwrap_or(Backend::Postgres);
+    let allowed_origins = settings
+        .parse("CORS_ORIGIN")?
+        .unwrap_or(AllowedOrigins::Any);
+    let cors_credentials = settings.parse("CORS_CREDENTIALS")?.unwrap_or(false);
+    // echoing any origin with credentials would let every site act as the user
+    if cors_credentials && matches!(allowed_origins, AllowedOrigins::Any) {
+        return Err(eyre!(
+            "`CORS_CREDENTIALS` needs `CORS_ORIGIN` to list the allowed origins"
+        ));
+    }
+    let allow_patch = settings.parse("ALLOW_PATCH")?.unwrap_or(false);
+    let id_mode = settings.parse("ID_MODE")?.unwrap_or(IdMode::Ulid);
+    if id_mode == IdMode::Short && backend != Backend::Postgres {
+        return Err(eyre!("`ID_MODE=short` is only supported with postgres"));
+    }
+    let replica_options = settings
+        .var("DATABASE_URL_REPLICA")
+        .map(|url| PgConnectOptions::from_str(&url))
+        .transpose()
+        .context("failed to parse `DATABASE_URL_REPLICA`")?;
+    if replica_options.is_some() && backend != Backend::Postgres {
+        return Err(eyre!(
+            "`DATABASE_URL_REPLICA` is only supported with postgres"
+        ));
+    }
+    let precompute_gzip = settings.parse("PRECOMPUTE_GZIP")?.unwrap_or(false);
+    if precompute_gzip && backend != Backend::Postgres {
+        return Err(eyre!("`PRECOMPUTE_GZIP` is only supported with postgres"));
+    }
+    let json_errors_enabled = settings.parse("JSON_ERRORS")?.unwrap_or(false);
+    let not_found_redirect = settings
+        .var("NOT_FOUND_REDIRECT")
+        .map(HeaderValue::try_from)
+        .transpose()
+        .context("failed to parse `NOT_FOUND_REDIRECT`")?;
+    let maintenance = settings.parse("MAINTENANCE")?.unwrap_or(false);
+    let cache_control = settings
+        .var("CACHE_CONTROL")
+        .map(|v| HeaderValue::from_str(&v))
+        .transpose()
+        .context("failed to parse `CACHE_CONTROL`")?
+        // entries that can change have to be revalidated
+        .unwrap_or(if allow_patch {
+            MUTABLE_CACHE_CONTROL
+        } else {
+            CACHE_CONTROL_VALUE
+        });
+    let listen = settings
+        .var("LISTEN")
+        .map(Cow::Owned)
+        .unwrap_or("[::]:2799".into())
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(ListenAddr::from_str)
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to parse `LISTEN`")?;
+    if listen.is_empty() {
+        return Err(eyre!("`LISTEN` is set but contains no addresses"));
+    }
+    // with a trailing slash, so uploads go to `POST <base path>`
+    let base_path = settings
+        .var("BASE_PATH")
+        .map(|path| path.trim_matches('/').to_owned())
+        .filter(|path| !path.is_empty())
+        .map(|path| format!("/{path}/"));
+    let max_concurrency = settings.parse::<usize>("CONCURRENCY")?.unwrap_or(100);
+    // shared between the routes of each kind, and still capped by `CONCURRENCY`
+    let upload_limit = GlobalConcurrencyLimitLayer::new(
+        settings
+            .parse("UPLOAD_CONCURRENCY")?
+            .unwrap_or(max_concurrency),
+    );
+    let retrieve_limit = GlobalConcurrencyLimitLayer::new(
+        settings
+            .parse("RETRIEVE_CONCURRENCY")?
+            .unwrap_or(max_concurrency),
+    );
+    let db_inflight = settings
+        .parse::<NonZeroUsize>("DB_INFLIGHT")?
+        .map(|max| Arc::new(Semaphore::new(max.get())));
+    let max_upload = settings.parse("MAX_UPLOAD")?.unwrap_or(DEFAULT_MAX_UPLOAD);
+    let min_upload = settings.parse("MIN_UPLOAD")?.unwrap_or(1);
+    if min_upload >= max_upload {
+        return Err(eyre!("`MIN_UPLOAD` must be below `MAX_UPLOAD`"));
+    }
+    let origin_limits: OriginLimits = settings.parse("ORIGIN_MAX_UPLOAD")?.unwrap_or_default();
+    if origin_limits.0.values().any(|&max| min_upload >= max) {
+        return Err(eyre!(
+            "`MIN_UPLOAD` must be below every `ORIGIN_MAX_UPLOAD`"
+        ));
+    }
+    let quota = settings
+        .parse::<u64>("MAX_TOTAL_BYTES")?
+        .map(|max| Arc::new(Quota::new(max)));
+    let upload_timeout = Duration::from_secs(
+        settings
+            .parse::<NonZeroU64>("UPLOAD_TIMEOUT")?
+            .map_or(30, NonZeroU64::get),
+    );
+    let metrics_enabled = settings.parse("METRICS_ENABLED")?.unwrap_or(false);
+    let info_page = settings.parse("INFO_PAGE")?.unwrap_or(true);
+    let allow_indexing = settings.parse("ALLOW_INDEXING")?.unwrap_or(false);
+    let compression = settings
+        .parse("STORAGE_COMPRESSION")?
+        .unwrap_or(Encoding::Identity);
+    let dictionaries = match settings.var_os("ZSTD_DICT") {
+        Some(paths) => {
+            let mut dictionaries = Vec::new();
+            for path in std::env::split_paths(&paths) {
+                let data = std::fs::read(&path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                let dictionary = Dictionary::new(data)
+                    .with_context(|| format!("failed to load {}", path.display()))?;
+                dictionaries.push(dictionary);
+            }
+            if compression != Encoding::Zstd {
+                warn!(
+                    "`STORAGE_COMPRESSION` isn't `zstd`, `ZSTD_DICT` is only used to read old entries"
+                );
+            }
+            Dictionaries::new(dictionaries).context("failed to load `ZSTD_DICT`")?
+        }
+        None => Dictionaries::default(),
+    };
+    let api_keys = match settings.var("API_KEYS") {
+        Some(keys) => {
+            let keys: Arc<[String]> = keys
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_owned)
+                .collect();
+            if keys.is_empty() {
+                return Err(eyre!("`API_KEYS` is set but contains no keys"));
+            }
+            Some(keys)
+        }
+        None => None,
+    };
+    let upload_limiter = settings
+        .parse::<NonZeroU32>("UPLOAD_RATE")?
+        .map(|rate| {
+            let burst = settings.parse("UPLOAD_BURST")?.unwrap_or(rate);
+            eyre::Ok(Arc::new(RateLimiter::new(rate, burst)))
+        })
+        .transpose()?;
+    let key_rate = settings
+        .parse::<NonZeroU32>("KEY_UPLOAD_RATE")?
+        .map(|rate| eyre::Ok((rate, settings.parse("KEY_UPLOAD_BURST")?.unwrap_or(rate))))
+        .transpose()?;
+    let key_concurrency = settings.parse::<NonZeroUsize>("KEY_UPLOAD_CONCURRENCY")?;
+    let key_limits = (key_rate.is_some() || key_concurrency.is_some())
+        .then(|| Arc::new(KeyLimits::new(key_rate, key_concurrency)));
+    let trusted_proxies = settings
+        .var("TRUSTED_PROXIES")
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|cidr| !cidr.is_empty())
+                .map(Cidr::from_str)
+                .collect::<Result<Arc<[_]>, _>>()
+        })
+        .transpose()
+        .context("failed to parse `TRUSTED_PROXIES`")?;
+    let proxy_trust = match (
+        settings.parse("TRUST_PROXY")?.unwrap_or(false),
+        trusted_proxies,
+    ) {
+        (false, None) => ProxyTrust::None,
+        (false, Some(_)) => return Err(eyre!("`TRUSTED_PROXIES` needs `TRUST_PROXY`")),
+        (true, None) => ProxyTrust::Any,
+        (true, Some(cidrs)) => ProxyTrust::Cidrs(cidrs),
+    };
+    let allowed_content_types = settings
+        .var("ALLOWED_CONTENT_TYPES")
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|mime| !mime.is_empty())
+                .map(Mime::from_str)
+                .collect::<Result<Arc<[_]>, _>>()
+        })
+        .transpose()
+        .context("failed to parse `ALLOWED_CONTENT_TYPES`")?;
+    if allowed_content_types
+        .as_deref()
+        .is_some_and(<[_]>::is_empty)
+    {
+        return Err(eyre!(
+            "`ALLOWED_CONTENT_TYPES` is set but contains no types"
+        ));
+    }
+    let validate_scenes = settings.parse("VALIDATE_SCENES")?.unwrap_or(false);
+    let verify_on_read = settings.parse("VERIFY_ON_READ")?.unwrap_or(false);
+    let signer = settings
+        .var("SIGNING_KEY")
+        .filter(|key| !key.is_empty())
+        .map(Signer::new);
+    let signed_urls_required = settings.parse("SIGNED_URLS_REQUIRED")?.unwrap_or(false);
+    if signed_urls_required && signer.is_none() {
+        return Err(eyre!(
+            "`SIGNED_URLS_REQUIRED` is set, but `SIGNING_KEY` isn't"
+        ));
+    }
+    let webhooks = settings
+        .var("WEBHOOK_URL")
+        .map(|url| Webhooks::new(url, settings.var("WEBHOOK_SECRET")))
+        .transpose()
+        .context("failed to set up webhooks")?;
+    let cipher = settings.parse::<Cipher>("ENCRYPTION_KEY")?.map(Arc::new);
+    let admin_token: Option<Arc<str>> = settings
+        .var("ADMIN_TOKEN")
+        .filter(|token| !token.is_empty())
+        .map(Into::into);
+    let http2_cleartext = settings.parse("HTTP2_CLEARTEXT")?.unwrap_or(false);
+    let tcp = TcpOptions {
+        nodelay: settings.parse("TCP_NODELAY")?.unwrap_or(false),
+        keepalive: settings
+            .parse::<NonZeroU64>("TCP_KEEPALIVE_SECS")?
+            .map(|secs| Duration::from_secs(secs.get())),
+    };
+    let shutdown_timeout = Duration::from_secs(settings.parse("SHUTDOWN_TIMEOUT")?.unwrap_or(30));
+    let pre_stop_delay = Duration::from_secs(settings.parse("PRE_STOP_DELAY")?.unwrap_or(0));
+    let get_jitter = settings
+        .parse::<NonZeroU64>("GET_JITTER_MS")?
+        .map(|ms| Duration::from_millis(ms.get()));
+    let slow_request = settings
+        .parse::<NonZeroU64>("SLOW_REQUEST_MS")?
+        .map(|ms| Duration::from_millis(ms.get()));
+    let tls = match (settings.var_os("TLS_CERT"), settings.var_os("TLS_KEY")) {
+        (Some(cert), Some(key)) => {
+            let tls = Tls::load(cert.into(), key.into())
+                .context("failed to load tls certificate and key")?;
+            Some(Arc::new(tls))
+        }
+        (None, None) => None,
+        _ => return Err(eyre!("`TLS_CERT` and `TLS_KEY` must be set together")),
+    };
+    if tls.is_some()
+        && listen
+            .iter()
+            .any(|addr| matches!(addr, ListenAddr::Unix(_)))
+    {
+        return Err(eyre!("tls is not supported on unix sockets"));
+    }
+    let sweep_interval = Duration::from_secs(
+        settings
+            .parse::<NonZeroU64>("SWEEP_INTERVAL")?
+            .map_or(300, NonZeroU64::get),
+    );
+    let soft_delete = settings.parse("SOFT_DELETE")?.unwrap_or(false);
+    let max_entries = settings.parse::<NonZeroU64>("MAX_ENTRIES")?;
+    let max_ttl = settings.parse::<u64>("MAX_TTL")?;
+    let default_ttl = settings.parse::<u64>("DEFAULT_TTL")?;
+    if default_ttl
+        .zip(max_ttl)
+        .is_some_and(|(default_ttl, max_ttl)| default_ttl > max_ttl)
+    {
+        return Err(eyre!("`DEFAULT_TTL` is longer than `MAX_TTL`"));
+    }
+    if max_ttl
+        .or(default_ttl)
+        .is_some_and(|ttl| expiry(ttl).is_none())
+    {
+        return Err(eyre!("`DEFAULT_TTL` or `MAX_TTL` is too long"));
+    }
+    let ttl = TtlPolicy {
+        default: default_ttl,
+        max: max_ttl,
+        overflow: settings
+            .parse("TTL_OVERFLOW")?
+            .unwrap_or(TtlOverflow::Clamp),
+    };
+    let soft_delete_grace = TimeDelta::seconds(
+        settings
+            .parse::<u32>("SOFT_DELETE_GRACE")?
+            .map_or(DEFAULT_SOFT_DELETE_GRACE, i64::from),
+    );
+
+    let storage: Arc<dyn Storage> = match backend {
+        Backend::Postgres => {
+            let connect_options = database_options(&settings)?;
+            let max_connections = settings
+                .parse::<NonZeroU32>("DB_MAX_CONNECTIONS")?
+                .map_or(DEFAULT_MAX_CONNECTIONS, NonZeroU32::get);
+            let min_connections = settings.parse("DB_MIN_CONNECTIONS")?.unwrap_or(0);
+            if min_connections > max_connections {
+                return Err(eyre!(
+                    "`DB_MIN_CONNECTIONS` is greater than `DB_MAX_CONNECTIONS`"
+                ));
+            }
+            let connect_timeout =
+                Duration::from_secs(settings.parse("DB_CONNECT_TIMEOUT")?.unwrap_or(30));
+            info!(
+                "database pool: {min_connections} to {max_connections} connections, {}s connect timeout",
+                connect_timeout.as_secs()
+            );
+
+            // the replica gets a pool of the same size
+            let pool_options = PgPoolOptions::new()
+                .max_connections(max_connections)
+                .min_connections(min_connections)
+                .acquire_timeout(connect_timeout)
+                // connections that died with a restart of the database are
+                // thrown away instead of failing the request that gets them
+                .test_before_acquire(true)
+                .after_connect(|_, _| {
+                    Box::pin(async {
+                        debug!("opened database connection");
+                        Ok(())
+                    })
+                });
+            let pool = pool_options
+                .clone()
+                .connect_with(connect_options)
+                .await
+                .context("failed to connect to database")?;
+
+            let migrator = sqlx::migrate!("./migrations");
+            match settings.parse("MIGRATE")?.unwrap_or(MigrateMode::Auto) {
+                MigrateMode::Auto => migrator
+                    .run(&pool)
+                    .await
+                    .context("failed to run migrations")?,
+                MigrateMode::Skip => info!("not running migrations, `MIGRATE` is `skip`"),
+                MigrateMode::Verify => verify_migrations(&pool, &migrator).await?,
+            }
+
+            let attempts = settings
+                .parse("DB_RETRIES")?
+                .unwrap_or(NonZeroU32::new(3).unwrap());
+            let storage = PgStorage::new(pool, attempts);
+            let storage = match &replica_options {
+                Some(replica_options) => {
+                    let replica = pool_options
+                        .connect_with(replica_options.clone())
+                        .await
+                        .context("failed to connect to replica database")?;
+                    let lag = TimeDelta::seconds(
+                        settings
+                            .parse::<u32>("REPLICA_LAG")?
+                            .map_or(DEFAULT_REPLICA_LAG, i64::from),
+                    );
+                    storage.with_replica(replica, lag)
+                }
+                None => storage,
+            };
+            Arc::new(storage)
+        }
+        Backend::Fs => {
+            let data_dir: PathBuf = settings
+                .var_os("DATA_DIR")
+                .ok_or_else(|| eyre!("`DATA_DIR` not set"))?
+                .into();
+            let storage = FsStorage::new(data_dir.clone())
+                .await
+                .with_context(|| format!("failed to create {}", data_dir.display()))?;
+            Arc::new(storage)
+        }
+        Backend::S3 => {
+            let bucket = settings
+                .var("S3_BUCKET")
+                .ok_or_else(|| eyre!("`S3_BUCKET` not set"))?;
+            let prefix = settings.var("S3_PREFIX").unwrap_or_default();
+            let path_style = settings.parse("S3_PATH_STYLE")?.unwrap_or(false);
+            // endpoint, region and credentials come from the usual `AWS_*` variables
+            let config = aws_config::load_from_env().await;
+            let config = aws_sdk_s3::config::Builder::from(&config)
+                .force_path_style(path_style)
+                .build();
+            Arc::new(S3Storage::new(
+                aws_sdk_s3::Client::from_conf(config),
+                bucket,
+                prefix,
+            ))
+        }
+    };
+
+    tokio::spawn(sweep(storage.clone(), sweep_interval, soft_delete_grace));
+    let storage_healthy = Arc::new(AtomicBool::new(true));
+    tokio::spawn(watch_storage(storage.clone(), storage_healthy.clone()));
+    let eviction = max_entries.map(|max| {
+        let wakeup = Arc::new(Notify::new());
+        // in case there are too many entries already
+        wakeup.notify_one();
+        tokio::spawn(evict(storage.clone(), max.get(), wakeup.clone()));
+        wakeup
+    });
+    if let Some(limiter) = upload_limiter.clone() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                limiter.retain_recent();
+            }
+        });
+    }
+    if let Some(limits) = key_limits.clone() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                limits.retain_recent();
+            }
+        });
+    }
+
+    // one event so it can be picked out of the logs as a whole, secrets are
+    // only logged as whether they are set
+    info!(
+        ?backend,
+        replica = replica_options.is_some(),
+        listen = %listen.iter().map(ToString::to_string).collect::<Vec<_>>().join(","),
+        base_path = base_path.as_deref().unwrap_or("/"),
+        cors_origin = %allowed_origins,
+        cors_credentials,
+        concurrency = max_concurrency,
+        max_upload,
+        min_upload,
+        upload_timeout_secs = upload_timeout.as_secs(),
+        get_jitter_ms = get_jitter.map(|jitter| jitter.as_millis() as u64),
+        default_ttl = ?ttl.default,
+        max_ttl = ?ttl.max,
+        api_keys = api_keys.as_ref().map_or(0, |keys| keys.len()),
+        admin = admin_token.is_some(),
+        tls = tls.is_some(),
+        metrics = metrics_enabled,
+        encryption = cipher.is_some(),
+        signed_urls = signer.is_some(),
+        webhooks = webhooks.is_some(),
+        trust_proxy = !matches!(proxy_trust, ProxyTrust::None),
+        maintenance,
+        "effective configuration"
+    );
+
+    let metrics = Arc::new(Metrics::new().context("failed to register metrics")?);
+    let (shutting_down, _) = watch::channel(false);
+
+    let state = AppState {
+        storage,
+        allowed_origins,
+        cors_credentials,
+        cache_control,
+        max_upload,
+        origin_limits: Arc::new(origin_limits),
+        min_upload,
+        upload_timeout,
+        ttl,
+        quota: quota.clone(),
+        metrics,
+        compression,
+        dictionaries: Arc::new(dictionaries),
+        api_keys,
+        upload_limiter,
+        key_limits,
+        proxy_trust,
+        allowed_content_types,
+        validate_scenes,
+        verify_on_read,
+        cipher,
+        admin_token: admin_token.clone(),
+        base_path: base_path.as_deref().unwrap_or("/").into(),
+        signer: signer.clone(),
+        signed_urls_required,
+        soft_delete,
+        allow_patch,
+        id_mode,
+        json_errors: json_errors_enabled,
+        maintenance: Arc::new(AtomicBool::new(maintenance)),
+        storage_healthy,
+        in_flight: Arc::new(AtomicUsize::new(0)),
+        ids: Arc::new(Ids::default()),
+        precompute_gzip,
+        not_found_redirect,
+        db_inflight,
+        allow_indexing,
+        slow_request,
+        views: Arc::new(Views::default()),
+        eviction,
+        webhooks,
+        sessions: Arc::new(Sessions::default()),
+        shutting_down: shutting_down.subscribe(),
+    };
+    let in_flight = state.in_flight.clone();
+    // views are flushed periodically, and once more when shutting down
+    let (views, views_storage) = (state.views.clone(), state.storage.clone());
+    tokio::spawn({
+        let sessions = state.sessions.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let dropped = sessions.remove_idle(SESSION_IDLE_TIMEOUT);
+                if dropped > 0 {
+                    info!("dropped {dropped} idle resumable uploads");
+                }
+            }
+        }
+    });
+    if let Some(quota) = quota {
+        let storage = state.storage.clone();
+        tokio::spawn(async move {
+            quota
+                .refresh_periodically(&*storage, QUOTA_REFRESH_INTERVAL)
+                .await
+        });
+    }
+    tokio::spawn({
+        let (views, storage) = (views.clone(), views_storage.clone());
+        async move {
+            views
+                .flush_periodically(&*storage, VIEWS_FLUSH_INTERVAL)
+                .await
+        }
+    });
+
+    // only picks when to respond, the waiting is left to `hold_back`
+    let jitter = middleware::from_fn_with_state(get_jitter, add_jitter);
+    let entry_routes = get(retrieve.layer(retrieve_limit.clone()).layer(jitter.clone()))
+        .head(head.layer(retrieve_limit.clone()).layer(jitter.clone()))
+        .put(create.layer(upload_limit.clone()))
+        .delete(delete)
+        .options(handle_options);
+    let entry_routes = if allow_patch {
+        entry_routes
+            .patch(replace.layer(upload_limit.clone()))
+            .fallback(method_not_allowed("GET, HEAD, PUT, PATCH, DELETE, OPTIONS"))
+    } else {
+        entry_routes.fallback(method_not_allowed("GET, HEAD, PUT, DELETE, OPTIONS"))
+    };
+    let root_routes = post(upload.layer(upload_limit.clone())).options(handle_options);
+    let root_routes = if info_page {
+        root_routes
+            .get(info)
+            .fallback(method_not_allowed("GET, HEAD, POST, OPTIONS"))
+    } else {
+        root_routes.fallback(method_not_allowed("POST, OPTIONS"))
+    };
+    let mut app = Router::new()
+        .route("/", root_routes)
+        .route("/{id}", entry_routes)
+        .route(
+            "/uploads",
+            post(create_session.layer(upload_limit.clone()))
+                .options(handle_options)
+                .fallback(method_not_allowed("POST, OPTIONS")),
+        )
+        .route(
+            "/uploads/{id}",
+            patch(append_session.layer(upload_limit.clone()))
+                .head(session_offset)
+                .options(handle_options)
+                .fallback(method_not_allowed("HEAD, PATCH, OPTIONS")),
+        )
+        .route(
+            "/uploads/{id}/complete",
+            post(complete_session.layer(upload_limit))
+                .options(handle_options)
+                .fallback(method_not_allowed("POST, OPTIONS")),
+        )
+        .route(
+            "/batch",
+            post(batch.layer(retrieve_limit.clone()).layer(jitter.clone()))
+                .options(handle_options)
+                .fallback(method_not_allowed("POST, OPTIONS")),
+        )
+        .route(
+            "/openapi.json",
+            get(openapi).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/{id}/meta",
+            get(meta.layer(retrieve_limit.clone()).layer(jitter.clone()))
+                .options(handle_options)
+                .fallback(method_not_allowed("GET, HEAD, OPTIONS")),
+        )
+        .route(
+            "/{id}/exists",
+            get(exists.layer(retrieve_limit).layer(jitter))
+                .options(handle_options)
+                .fallback(method_not_allowed("GET, HEAD, OPTIONS")),
+        )
+        .route(
+            "/{id}/touch",
+            post(touch)
+                .options(handle_options)
+                .fallback(method_not_allowed("POST, OPTIONS")),
+        );
+    if admin_token.is_some() {
+        app = app.route(
+            "/admin/entries",
+            get(list_entries).fallback(method_not_allowed("GET, HEAD")),
+        );
+        app = app.route(
+            "/admin/entries/{id}/restore",
+            post(restore).fallback(method_not_allowed("POST")),
+        );
+        if signer.is_some() {
+            app = app.route(
+                "/admin/entries/{id}/sign",
+                post(sign).fallback(method_not_allowed("POST")),
+            );
+        }
+        app = app.route(
+            "/admin/export",
+            get(export).fallback(method_not_allowed("GET, HEAD")),
+        );
+        app = app.route(
+            "/admin/archive",
+            post(archive).fallback(method_not_allowed("POST")),
+        );
+        app = app.route(
+            "/admin/import",
+            post(import).fallback(method_not_allowed("POST")),
+        );
+        app = app.route(
+            "/admin/maintenance",
+            get(get_maintenance)
+                .put(set_maintenance)
+                .fallback(method_not_allowed("GET, HEAD, PUT")),
+        );
+        app = app.route(
+            "/stats",
+            get(stats).fallback(method_not_allowed("GET, HEAD")),
+        );
+    }
+    if metrics_enabled {
+        app = app.route(
+            "/metrics",
+            get(export_metrics).fallback(method_not_allowed("GET, HEAD")),
+        );
+    }
+    let app = app
+        .fallback(options(handle_options))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(Semaphore::new(max_concurrency)),
+            limit_concurrency,
+        ))
+        .layer(middleware::from_fn(hold_back))
+        // added after the concurrency limit so it stays responsive under load
+        .route(
+            "/health",
+            get(health).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/livez",
+            get(livez).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/version",
+            get(version).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/readyz",
+            get(readyz).fallback(method_not_allowed("GET, HEAD")),
+        );
+    let app = match &base_path {
+        Some(base_path) => Router::new().nest(base_path, app),
+        None => app,
+    }
+    // crawlers only look for it at the root, whatever `BASE_PATH` is
+    .route(
+        "/robots.txt",
+        get(robots).fallback(method_not_allowed("GET, HEAD")),
+    );
+    let app = app
+        .layer(middleware::from_fn_with_state(state.clone(), json_errors))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            cors::add_headers,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            observe_request,
+        ))
+        .layer(trace::propagate_request_id())
+        .layer(trace::trace_layer())
+        .layer(trace::set_request_id())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            track_in_flight,
+        ))
+        .with_state(state);
+
+    // readiness fails as soon as shutdown is asked for, but requests are only
+    // drained once load balancers had `PRE_STOP_DELAY` to stop sending them
+    let (draining, _) = watch::channel(false);
+    tokio::spawn(shutdown_signal(
+        shutting_down.clone(),
+        draining.clone(),
+        pre_stop_delay,
+    ));
+    // there is no SIGHUP elsewhere, certificates are only loaded on startup there
+    #[cfg(unix)]
+    if let Some(tls) = &tls {
+        tokio::spawn(tls.clone().reload_on_sighup());
+    }
+    let mut servers = Vec::with_capacity(listen.len());
+    for addr in &listen {
+        let mut draining = draining.subscribe();
+        let shutdown = async move {
+            let _ = draining.wait_for(|&v| v).await;
+        };
+        servers.push(
+            serve(
+                addr,
+                app.clone(),
+                tls.clone(),
+                tcp,
+                http2_cleartext,
+                shutdown,
+            )
+            .await?,
+        );
+    }
+    let server = future::try_join_all(servers);
+
+    // don't let requests that never finish hold up the shutdown forever
+    let drain_timeout = async {
+        let _ = draining.subscribe().wait_for(|&v| v).await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    select! {
+        result = server => {
+            result.context("failed to serve app")?;
+        }
+        _ = drain_timeout => {
+            warn!(
+                "{} requests still pending after {}s, exiting anyway",
+                in_flight.load(Ordering::Relaxed),
+                shutdown_timeout.as_secs(),
+            );
+        }
+    }
+
+    views.flush(&*views_storage).await;
+
+    for addr in &listen {
+        if let ListenAddr::Unix(path) = addr {
+            remove_socket(path)
+                .unwrap_or_else(|err| warn!("failed to remove socket {}: {err}", path.display()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// state with everything but the fs backend at its defaults, and the directory it stores in
+    async fn fs_state() -> (AppState, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("excalidraw-api-mini-{}", Ulid::new()));
+        let storage = FsStorage::new(dir.clone()).await.unwrap();
+        let (_, shutting_down) = watch::channel(false);
+        let state = AppState {
+            storage: Arc::new(storage),
+            allowed_origins: AllowedOrigins::Any,
+            cors_credentials: false,
+            cache_control: HeaderValue::from_static("no-cache"),
+            max_upload: 1 << 20,
+            origin_limits: Arc::default(),
+            min_upload: 1,
+            upload_timeout: Duration::from_secs(30),
+            ttl: TtlPolicy {
+                default: None,
+                max: None,
+                overflow: TtlOverflow::Clamp,
+            },
+            quota: None,
+            metrics: Arc::new(Metrics::new().unwrap()),
+            compression: Encoding::Identity,
+            dictionaries: Arc::default(),
+            api_keys: None,
+            upload_limiter: None,
+            key_limits: None,
+            proxy_trust: ProxyTrust::default(),
+            allowed_content_types: None,
+            validate_scenes: false,
+            verify_on_read: false,
+            cipher: None,
+            admin_token: None,
+            base_path: "/".into(),
+            signer: None,
+            signed_urls_required: false,
+            soft_delete: false,
+            allow_patch: false,
+            id_mode: IdMode::Ulid,
+            json_errors: false,
+            maintenance: Arc::default(),
+            storage_healthy: Arc::new(AtomicBool::new(true)),
+            in_flight: Arc::default(),
+            ids: Arc::default(),
+            precompute_gzip: false,
+            not_found_redirect: None,
+            db_inflight: None,
+            allow_indexing: false,
+            slow_request: None,
+            views: Arc::default(),
+            eviction: None,
+            webhooks: None,
+            sessions: Arc::default(),
+            shutting_down,
+        };
+        (state, dir)
+    }
+
+    /// uploads `value` like `POST /` would, returning the id it is stored at
+    async fn upload_value(state: &AppState, value: &'static [u8]) -> Ulid {
+        let headers = HeaderMap::new();
+        let Ok(upload) = read_upload(state, None, false, &headers, Body::from(value)).await else {
+            panic!("upload was rejected");
+        };
+        save(state, upload).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn same_value_same_id() {
+        let (state, dir) = fs_state().await;
+        let first = upload_value(&state, b"{\"elements\":[]}").await;
+        let second = upload_value(&state, b"{\"elements\":[]}").await;
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn different_value_different_id() {
+        let (state, dir) = fs_state().await;
+        let first = upload_value(&state, b"{\"elements\":[]}").await;
+        let second = upload_value(&state, b"{\"elements\":[{}]}").await;
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+        assert_ne!(first, second);
+    }
 }
@@ -0,0 +1,108 @@
+use std::fmt::{self, Debug};
+use std::time::Duration;
+
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+    exponential_buckets,
+};
+
+/// prometheus counters for the upload and retrieve handlers
+pub struct Metrics {
+    registry: Registry,
+    pub uploads: IntCounter,
+    pub upload_size: Histogram,
+    pub too_large: IntCounter,
+    pub timed_out: IntCounter,
+    pub retrieval_hits: IntCounter,
+    pub retrieval_misses: IntCounter,
+    request_duration: HistogramVec,
+    response_size: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+
+        let uploads = IntCounter::new("excalidraw_uploads_total", "number of stored uploads")?;
+        let upload_size = Histogram::with_opts(
+            HistogramOpts::new("excalidraw_upload_size_bytes", "size of stored uploads")
+                // 1 KiB to 16 MiB
+                .buckets(exponential_buckets(1024.0, 4.0, 8)?),
+        )?;
+        let too_large = IntCounter::new(
+            "excalidraw_uploads_too_large_total",
+            "number of uploads rejected for exceeding the size limit",
+        )?;
+        let timed_out = IntCounter::new(
+            "excalidraw_uploads_timed_out_total",
+            "number of uploads rejected for taking longer than `UPLOAD_TIMEOUT`",
+        )?;
+        let retrievals = IntCounterVec::new(
+            Opts::new("excalidraw_retrievals_total", "number of retrievals"),
+            &["result"],
+        )?;
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "excalidraw_request_duration_seconds",
+                "time taken to respond to requests",
+            )
+            // 1ms to ~16s
+            .buckets(exponential_buckets(0.001, 2.0, 15)?),
+            &["method", "route"],
+        )?;
+        let response_size = HistogramVec::new(
+            HistogramOpts::new(
+                "excalidraw_response_size_bytes",
+                "size of response bodies, if known up front",
+            )
+            // 1 KiB to 16 MiB
+            .buckets(exponential_buckets(1024.0, 4.0, 8)?),
+            &["method", "route"],
+        )?;
+
+        registry.register(Box::new(uploads.clone()))?;
+        registry.register(Box::new(upload_size.clone()))?;
+        registry.register(Box::new(too_large.clone()))?;
+        registry.register(Box::new(timed_out.clone()))?;
+        registry.register(Box::new(retrievals.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(response_size.clone()))?;
+
+        Ok(Self {
+            registry,
+            uploads,
+            upload_size,
+            too_large,
+            timed_out,
+            retrieval_hits: retrievals.with_label_values(&["hit"]),
+            retrieval_misses: retrievals.with_label_values(&["miss"]),
+            request_duration,
+            response_size,
+        })
+    }
+
+    /// records how long a request to `route` took, and how big its response was
+    pub fn observe_request(&self, method: &str, route: &str, elapsed: Duration, size: Option<u64>) {
+        let labels = [method, route];
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+        if let Some(size) = size {
+            self.response_size
+                .with_label_values(&labels)
+                .observe(size as f64);
+        }
+    }
+
+    /// renders all metrics in the prometheus text format
+    pub fn encode(&self) -> prometheus::Result<String> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
+
+impl Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
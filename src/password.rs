@@ -0,0 +1,48 @@
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use axum::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// header with the password of a protected entry
+///
+/// a header rather than a query parameter, so it never ends up in the logs.
+pub const X_ENTRY_PASSWORD: HeaderName = HeaderName::from_static("x-entry-password");
+
+/// an empty or non-utf-8 password header
+#[derive(Debug)]
+pub struct InvalidPassword;
+
+/// the password in the request, if there is one
+pub fn from_headers(headers: &HeaderMap) -> Result<Option<&str>, InvalidPassword> {
+    match headers.get(X_ENTRY_PASSWORD).map(HeaderValue::to_str) {
+        Some(Ok(password)) if !password.is_empty() => Ok(Some(password)),
+        Some(_) => Err(InvalidPassword),
+        None => Ok(None),
+    }
+}
+
+/// hashes a password with argon2, which is slow, so it is done on a blocking thread
+pub async fn hash(password: String) -> Result<String, password_hash::Error> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .expect("password hashing panicked")
+}
+
+/// checks a password against a hash from `hash`, in constant time
+pub async fn verify(hash: String, password: String) -> Result<bool, password_hash::Error> {
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&hash)?;
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(err),
+        }
+    })
+    .await
+    .expect("password verification panicked")
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+
+use crate::storage::Storage;
+
+/// keeps track of how much storage is used, so uploads can be rejected before it runs out
+///
+/// the usage is only an estimate, it grows with every upload and is corrected
+/// by asking storage every now and then, which also picks up deletions.
+#[derive(Debug)]
+pub struct Quota {
+    max: u64,
+    used: AtomicU64,
+}
+
+impl Quota {
+    pub fn new(max: u64) -> Self {
+        Self {
+            max,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// whether `length` more bytes can be stored
+    ///
+    /// uploads checked at the same time can go over the quota together.
+    pub fn has_room(&self, length: u64) -> bool {
+        self.used.load(Ordering::Relaxed).saturating_add(length) <= self.max
+    }
+
+    pub fn add(&self, length: u64) {
+        self.used.fetch_add(length, Ordering::Relaxed);
+    }
+
+    /// swaps `old` bytes for `new` ones, for values replaced in place
+    pub fn replace(&self, old: u64, new: u64) {
+        self.add(new);
+        let _ = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(old))
+            });
+    }
+
+    /// replaces the estimate with what storage reports, keeping it if that fails
+    pub async fn refresh(&self, storage: &dyn Storage) {
+        match storage.total_length().await {
+            Ok(used) => self.used.store(used, Ordering::Relaxed),
+            Err(err) => warn!("failed to check storage usage: {err}"),
+        }
+    }
+
+    pub async fn refresh_periodically(&self, storage: &dyn Storage, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.refresh(storage).await;
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::FromRequestParts;
+use axum::http::header::{HeaderValue, RETRY_AFTER};
+use axum::http::request::Parts;
+use axum::http::status::StatusCode;
+use axum::response::{IntoResponse, Response};
+use governor::clock::{Clock, DefaultClock};
+use governor::{DefaultKeyedRateLimiter, Quota};
+use sha2::{Digest, Sha256};
+
+use crate::client_ip::ClientIp;
+use crate::{AppState, api_error, bearer_token};
+
+/// how long to wait for an upload of the same key to finish, in seconds
+const KEY_BUSY_RETRY_AFTER: u64 = 1;
+
+/// token bucket rate limiter keyed by client ip
+pub struct RateLimiter {
+    limiter: DefaultKeyedRateLimiter<IpAddr>,
+    clock: DefaultClock,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: NonZeroU32, burst: NonZeroU32) -> Self {
+        Self {
+            limiter: governor::RateLimiter::keyed(Quota::per_minute(per_minute).allow_burst(burst)),
+            clock: DefaultClock::default(),
+        }
+    }
+
+    /// takes a token from the bucket of `ip`, or returns how long to wait until one is available
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.limiter
+            .check_key(&bucket(ip))
+            .map_err(|not_until| not_until.wait_time_from(self.clock.now()))
+    }
+
+    /// forgets about clients whose buckets are full again
+    pub fn retain_recent(&self) {
+        self.limiter.retain_recent();
+    }
+}
+
+impl Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter").finish_non_exhaustive()
+    }
+}
+
+/// clients usually get a whole /64, so limit those as one
+fn bucket(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX))),
+        ip => ip,
+    }
+}
+
+fn rate_limited(wait: Duration) -> Response {
+    let retry_after = wait.as_secs_f64().ceil() as u64;
+    (
+        [(RETRY_AFTER, HeaderValue::from(retry_after))],
+        api_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "RateLimitError",
+            &format!("too many uploads, retry in {retry_after} seconds"),
+        ),
+    )
+        .into_response()
+}
+
+/// who per-key limits are counted against
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Tenant {
+    /// sha-256 of the api key, so keys aren't kept around in another place
+    Key(Vec<u8>),
+    Ip(IpAddr),
+}
+
+/// upload rate and concurrency limits for each api key, or each client ip
+/// if `API_KEYS` isn't set
+pub struct KeyLimits {
+    rate: Option<DefaultKeyedRateLimiter<Tenant>>,
+    concurrency: Option<NonZeroUsize>,
+    in_flight: Mutex<HashMap<Tenant, usize>>,
+    clock: DefaultClock,
+}
+
+impl KeyLimits {
+    /// `rate` is uploads per minute and the burst on top of it
+    pub fn new(rate: Option<(NonZeroU32, NonZeroU32)>, concurrency: Option<NonZeroUsize>) -> Self {
+        Self {
+            rate: rate.map(|(per_minute, burst)| {
+                governor::RateLimiter::keyed(Quota::per_minute(per_minute).allow_burst(burst))
+            }),
+            concurrency,
+            in_flight: Mutex::default(),
+            clock: DefaultClock::default(),
+        }
+    }
+
+    /// counts an upload of `tenant` as in flight, unless it has as many as it can have
+    fn acquire(self: &Arc<Self>, tenant: &Tenant) -> Option<KeyPermit> {
+        if let Some(concurrency) = self.concurrency {
+            let mut in_flight = self.in_flight.lock().expect("in flight lock poisoned");
+            let count = in_flight.entry(tenant.clone()).or_default();
+            if *count >= concurrency.get() {
+                return None;
+            }
+            *count += 1;
+        }
+        Some(KeyPermit {
+            limits: self.clone(),
+            tenant: tenant.clone(),
+        })
+    }
+
+    /// takes a token from the bucket of `tenant`, or returns how long to wait until one is available
+    fn check(&self, tenant: &Tenant) -> Result<(), Duration> {
+        let Some(rate) = &self.rate else {
+            return Ok(());
+        };
+        rate.check_key(tenant)
+            .map_err(|not_until| not_until.wait_time_from(self.clock.now()))
+    }
+
+    /// forgets about tenants whose buckets are full again
+    pub fn retain_recent(&self) {
+        if let Some(rate) = &self.rate {
+            rate.retain_recent();
+        }
+    }
+}
+
+impl Debug for KeyLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyLimits")
+            .field("concurrency", &self.concurrency)
+            .finish_non_exhaustive()
+    }
+}
+
+/// an upload counted as in flight for its tenant until this is dropped
+struct KeyPermit {
+    limits: Arc<KeyLimits>,
+    tenant: Tenant,
+}
+
+impl Drop for KeyPermit {
+    fn drop(&mut self) {
+        if self.limits.concurrency.is_none() {
+            return;
+        }
+        let mut in_flight = self
+            .limits
+            .in_flight
+            .lock()
+            .expect("in flight lock poisoned");
+        if let Some(count) = in_flight.get_mut(&self.tenant) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.tenant);
+            }
+        }
+    }
+}
+
+/// holds one of the uploads the api key (or client ip) can have in flight,
+/// rejecting the request with `503 Service Unavailable` if it has none left,
+/// or `429 Too Many Requests` if it is over its rate limit
+pub struct KeyLimit {
+    _permit: Option<KeyPermit>,
+}
+
+impl FromRequestParts<AppState> for KeyLimit {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(limits) = &state.key_limits else {
+            return Ok(Self { _permit: None });
+        };
+        // tokens only tell tenants apart once `Authorized` has checked them
+        let tenant = match bearer_token(parts).filter(|_| state.api_keys.is_some()) {
+            Some(key) => Tenant::Key(Sha256::digest(key).to_vec()),
+            None => {
+                let Ok(ClientIp(Some(ip))) = ClientIp::from_request_parts(parts, state).await
+                else {
+                    return Ok(Self { _permit: None });
+                };
+                Tenant::Ip(bucket(ip))
+            }
+        };
+        let Some(permit) = limits.acquire(&tenant) else {
+            return Err((
+                [(RETRY_AFTER, HeaderValue::from(KEY_BUSY_RETRY_AFTER))],
+                api_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "KeyBusyError",
+                    "too many uploads in progress for this key, try again later",
+                ),
+            )
+                .into_response());
+        };
+        limits.check(&tenant).map_err(rate_limited)?;
+        Ok(Self {
+            _permit: Some(permit),
+        })
+    }
+}
+
+/// rejects the request with `429 Too Many Requests` if the client is over the upload rate limit
+pub struct UploadRateLimit;
+
+impl FromRequestParts<AppState> for UploadRateLimit {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(limiter) = &state.upload_limiter else {
+            return Ok(Self);
+        };
+        let Ok(ClientIp(Some(ip))) = ClientIp::from_request_parts(parts, state).await else {
+            return Ok(Self);
+        };
+        limiter.check(ip).map_err(rate_limited)?;
+        Ok(Self)
+    }
+}
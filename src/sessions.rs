@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use axum::http::header::HeaderName;
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::Instant;
+use ulid::Ulid;
+
+/// how many bytes of a resumable upload the server has, sent by and to the client
+pub const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+/// how many resumable uploads can be in progress at once, they are buffered in memory
+const MAX_SESSIONS: usize = 100;
+
+/// a resumable upload, sent in parts and stored once completed
+#[derive(Debug)]
+pub struct Session {
+    pub content_type: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub password_hash: Option<String>,
+    /// everything received so far, uncompressed
+    pub received: Vec<u8>,
+    pub updated_at: Instant,
+}
+
+/// resumable uploads in progress
+///
+/// sessions only live in memory, so they are lost when restarting. each one is
+/// locked while a request appends to or completes it.
+#[derive(Default, Debug)]
+pub struct Sessions(Mutex<HashMap<Ulid, Arc<AsyncMutex<Session>>>>);
+
+impl Sessions {
+    fn lock(&self) -> MutexGuard<'_, HashMap<Ulid, Arc<AsyncMutex<Session>>>> {
+        self.0.lock().expect("sessions lock poisoned")
+    }
+
+    /// starts a session, `None` if there are too many already
+    pub fn create(&self, session: Session) -> Option<Ulid> {
+        let mut sessions = self.lock();
+        if sessions.len() >= MAX_SESSIONS {
+            return None;
+        }
+        let id = Ulid::new();
+        sessions.insert(id, Arc::new(AsyncMutex::new(session)));
+        Some(id)
+    }
+
+    pub fn get(&self, id: Ulid) -> Option<Arc<AsyncMutex<Session>>> {
+        self.lock().get(&id).cloned()
+    }
+
+    /// whether the session is still in progress, for requests that waited for its lock
+    pub fn contains(&self, id: Ulid) -> bool {
+        self.lock().contains_key(&id)
+    }
+
+    pub fn remove(&self, id: Ulid) -> bool {
+        self.lock().remove(&id).is_some()
+    }
+
+    /// drops sessions that weren't appended to for `idle`, returning how many
+    pub fn remove_idle(&self, idle: Duration) -> usize {
+        let mut sessions = self.lock();
+        let before = sessions.len();
+        // sessions in use are locked, and clearly not idle
+        sessions.retain(|_, session| match session.try_lock() {
+            Ok(session) => session.updated_at.elapsed() < idle,
+            Err(_) => true,
+        });
+        before - sessions.len()
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use ulid::Ulid;
+
+use crate::storage::hex;
+
+/// signs links to entries that stop working after some time, with hmac-sha256
+#[derive(Clone)]
+pub struct Signer(Arc<[u8]>);
+
+impl Signer {
+    pub fn new(key: String) -> Self {
+        Self(key.into_bytes().into())
+    }
+
+    fn mac(&self, id: Ulid, expires: i64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("hmac takes keys of any length");
+        mac.update(format!("{id}:{expires}").as_bytes());
+        mac
+    }
+
+    /// the hex encoded signature of a link to `id` that expires at the unix timestamp `expires`
+    pub fn sign(&self, id: Ulid, expires: i64) -> String {
+        hex(&self.mac(id, expires).finalize().into_bytes())
+    }
+
+    /// checks a signature from `sign`, in constant time
+    pub fn verify(&self, id: Ulid, expires: i64, signature: &str) -> bool {
+        unhex(signature)
+            .is_some_and(|signature| self.mac(id, expires).verify_slice(&signature).is_ok())
+    }
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Signer { .. }")
+    }
+}
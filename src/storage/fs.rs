@@ -0,0 +1,526 @@
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use ulid::Ulid;
+
+use super::{
+    ABANDONED_AFTER, Entry, Metadata, NewEntry, Replacement, Staged, Storage, StorageError, hex,
+    ulid_time,
+};
+use crate::encoding::Encoding;
+
+/// directory mapping hashes to the id of an entry with that hash
+const HASHES: &str = "hashes";
+/// directory values are written to before their entry is committed
+const STAGING: &str = "staging";
+
+/// metadata stored next to each value as `<id>.json`
+#[derive(Serialize, Deserialize, Debug)]
+struct Sidecar {
+    encoding: Option<String>,
+    #[serde(default)]
+    dictionary_id: Option<u32>,
+    size: u64,
+    content_type: Option<String>,
+    /// hex encoded sha-256 of the uncompressed value
+    hash: String,
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    views: u64,
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    deleted_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    accessed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    modified_at: Option<DateTime<Utc>>,
+}
+
+impl Sidecar {
+    fn expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// whether the entry neither expired nor was soft-deleted
+    fn visible(&self) -> bool {
+        !self.expired() && self.deleted_at.is_none()
+    }
+
+    fn into_metadata(self, id: Ulid, length: u64) -> Result<Metadata, StorageError> {
+        Ok(Metadata {
+            encoding: Encoding::from_column(self.encoding.as_deref())?,
+            dictionary_id: self.dictionary_id,
+            encrypted: self.encrypted,
+            password_hash: self.password_hash,
+            length,
+            size: Some(self.size),
+            content_type: self.content_type,
+            hash: Some(self.hash),
+            created_at: self.created_at.unwrap_or_else(|| ulid_time(id)),
+            modified_at: self.modified_at,
+            expires_at: self.expires_at,
+            views: Some(self.views),
+        })
+    }
+}
+
+/// stores each value in `<dir>/<id>`, with its metadata in `<dir>/<id>.json`
+#[derive(Clone, Debug)]
+pub struct FsStorage {
+    dir: PathBuf,
+}
+
+impl FsStorage {
+    pub async fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(dir.join(HASHES)).await?;
+        fs::create_dir_all(dir.join(STAGING)).await?;
+        Ok(Self { dir })
+    }
+
+    fn value_path(&self, id: Ulid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn sidecar_path(&self, id: Ulid) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    fn hash_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(HASHES).join(hash)
+    }
+
+    /// writes the sidecar of a new entry, returning `false` if the id is taken
+    ///
+    /// the sidecar claims the id, but the entry only exists once its value
+    /// does, so the value must be written after this.
+    async fn create_sidecar(&self, id: Ulid, entry: &NewEntry<'_>) -> Result<bool, StorageError> {
+        let sidecar = Sidecar {
+            encoding: entry.encoding.to_column().map(str::to_owned),
+            dictionary_id: entry.dictionary_id,
+            size: entry.size,
+            content_type: entry.content_type.map(str::to_owned),
+            hash: hex(entry.hash),
+            expires_at: entry.expires_at,
+            created_at: Some(entry.created_at),
+            views: 0,
+            encrypted: entry.encrypted,
+            password_hash: entry.password_hash.map(str::to_owned),
+            deleted_at: None,
+            accessed_at: None,
+            modified_at: None,
+        };
+        Ok(create_atomic(&self.sidecar_path(id), &serde_json::to_vec(&sidecar)?).await?)
+    }
+
+    async fn write_hash(&self, id: Ulid, hash: &[u8]) -> io::Result<()> {
+        write_atomic(&self.hash_path(&hex(hash)), id.to_string().as_bytes()).await
+    }
+
+    /// removes the mapping from `hash` to `id`, unless another entry with the
+    /// same hash has taken it over
+    async fn remove_hash(&self, id: Ulid, hash: &str) -> io::Result<()> {
+        let hash_path = self.hash_path(hash);
+        if fs::read_to_string(&hash_path)
+            .await
+            .is_ok_and(|v| v == id.to_string())
+        {
+            remove(&hash_path).await?;
+        }
+        Ok(())
+    }
+
+    /// removes staged values that haven't been written to in a while
+    async fn sweep_staging(&self) -> io::Result<()> {
+        let mut dir = fs::read_dir(self.dir.join(STAGING)).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let modified = file.metadata().await?.modified()?;
+            let idle = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            if idle > ABANDONED_AFTER {
+                remove(&file.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// reads the metadata of an entry, even if it has expired or was soft-deleted
+    async fn read_sidecar(&self, id: Ulid) -> Result<Option<Sidecar>, StorageError> {
+        match fs::read(self.sidecar_path(id)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// reads the metadata of an entry, `None` if it has expired or was soft-deleted
+    async fn sidecar(&self, id: Ulid) -> Result<Option<Sidecar>, StorageError> {
+        Ok(self.read_sidecar(id).await?.filter(Sidecar::visible))
+    }
+
+    /// sets `deleted_at` of an entry that isn't expired, returning whether it changed
+    async fn set_deleted_at(
+        &self,
+        id: Ulid,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, StorageError> {
+        let Some(mut sidecar) = self.read_sidecar(id).await? else {
+            return Ok(false);
+        };
+        if sidecar.expired() || sidecar.deleted_at.is_some() == deleted_at.is_some() {
+            return Ok(false);
+        }
+        sidecar.deleted_at = deleted_at;
+        write_atomic(&self.sidecar_path(id), &serde_json::to_vec(&sidecar)?).await?;
+        Ok(true)
+    }
+
+    /// ids of all entries, including expired ones
+    async fn ids(&self) -> io::Result<Vec<Ulid>> {
+        let mut ids = Vec::new();
+        let mut dir = fs::read_dir(&self.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let name = file.file_name();
+            let id = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+                .and_then(|id| Ulid::from_string(id).ok());
+            ids.extend(id);
+        }
+        Ok(ids)
+    }
+}
+
+/// a unique temporary path next to `path`
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", Ulid::new()));
+    tmp.into()
+}
+
+/// writes to a temporary file first so readers never see a partially written file
+async fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = tmp_path(path);
+    fs::write(&tmp, data).await?;
+    fs::rename(&tmp, path).await
+}
+
+/// like `write_atomic`, but returns `false` instead of replacing an existing file
+async fn create_atomic(path: &Path, data: &[u8]) -> io::Result<bool> {
+    let tmp = tmp_path(path);
+    fs::write(&tmp, data).await?;
+    // unlike renaming, linking fails if the file exists
+    let created = match fs::hard_link(&tmp, path).await {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(false),
+        Err(err) => Err(err),
+    };
+    fs::remove_file(&tmp).await?;
+    created
+}
+
+/// removes a file, returning whether it existed
+async fn remove(path: &Path) -> io::Result<bool> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    async fn put(&self, id: Ulid, value: &[u8], entry: NewEntry<'_>) -> Result<bool, StorageError> {
+        if !self.create_sidecar(id, &entry).await? {
+            return Ok(false);
+        }
+        write_atomic(&self.value_path(id), value).await?;
+        // protected entries are never deduplicated against
+        if entry.password_hash.is_none() {
+            self.write_hash(id, entry.hash).await?;
+        }
+        Ok(true)
+    }
+
+    async fn stage(&self) -> Result<Option<Box<dyn Staged>>, StorageError> {
+        let path = self.dir.join(STAGING).join(Ulid::new().to_string());
+        let file = File::create_new(&path).await?;
+        Ok(Some(Box::new(FsStaged {
+            storage: self.clone(),
+            path,
+            file,
+        })))
+    }
+
+    async fn find_duplicate(
+        &self,
+        hash: &[u8],
+        content_type: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Option<Ulid>, StorageError> {
+        let id = match fs::read_to_string(self.hash_path(&hex(hash))).await {
+            Ok(id) => id,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let Ok(id) = Ulid::from_string(&id) else {
+            return Ok(None);
+        };
+        let Some(sidecar) = self.sidecar(id).await? else {
+            return Ok(None);
+        };
+
+        let lives_long_enough = match (sidecar.expires_at, expires_at) {
+            (None, _) => true,
+            (Some(existing), Some(new)) => existing >= new,
+            (Some(_), None) => false,
+        };
+        if sidecar.content_type.as_deref() == content_type
+            && sidecar.password_hash.is_none()
+            && lives_long_enough
+            && fs::try_exists(self.value_path(id)).await?
+        {
+            Ok(Some(id))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn get(&self, id: Ulid) -> Result<Option<Entry>, StorageError> {
+        let value = match fs::read(self.value_path(id)).await {
+            Ok(value) => value,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let Some(sidecar) = self.sidecar(id).await? else {
+            return Ok(None);
+        };
+        let length = value.len() as u64;
+        Ok(Some(Entry {
+            value,
+            metadata: sidecar.into_metadata(id, length)?,
+        }))
+    }
+
+    async fn metadata(&self, id: Ulid) -> Result<Option<Metadata>, StorageError> {
+        let Some(sidecar) = self.sidecar(id).await? else {
+            return Ok(None);
+        };
+        let length = match fs::metadata(self.value_path(id)).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(sidecar.into_metadata(id, length)?))
+    }
+
+    async fn add_views(&self, views: &[(Ulid, u64)]) -> Result<(), StorageError> {
+        // not atomic, so views can get lost when this races with itself
+        for &(id, count) in views {
+            if let Some(mut sidecar) = self.read_sidecar(id).await? {
+                sidecar.views += count;
+                sidecar.accessed_at = Some(Utc::now());
+                write_atomic(&self.sidecar_path(id), &serde_json::to_vec(&sidecar)?).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn replace(
+        &self,
+        id: Ulid,
+        value: &[u8],
+        replacement: Replacement<'_>,
+    ) -> Result<bool, StorageError> {
+        let Some(mut sidecar) = self.sidecar(id).await? else {
+            return Ok(false);
+        };
+        if !fs::try_exists(self.value_path(id)).await? {
+            return Ok(false);
+        }
+        // the replaced value isn't a duplicate of anything anymore
+        self.remove_hash(id, &sidecar.hash).await?;
+
+        sidecar.encoding = replacement.encoding.to_column().map(str::to_owned);
+        sidecar.dictionary_id = replacement.dictionary_id;
+        sidecar.encrypted = replacement.encrypted;
+        sidecar.size = replacement.size;
+        if let Some(content_type) = replacement.content_type {
+            sidecar.content_type = Some(content_type.to_owned());
+        }
+        sidecar.hash = hex(replacement.hash);
+        sidecar.modified_at = Some(replacement.modified_at);
+        // not atomic, reads in between can see the new value with the old metadata
+        write_atomic(&self.value_path(id), value).await?;
+        write_atomic(&self.sidecar_path(id), &serde_json::to_vec(&sidecar)?).await?;
+        Ok(true)
+    }
+
+    async fn delete(&self, id: Ulid) -> Result<bool, StorageError> {
+        let existed = remove(&self.value_path(id)).await?;
+        if let Some(sidecar) = self.read_sidecar(id).await? {
+            self.remove_hash(id, &sidecar.hash).await?;
+        }
+        remove(&self.sidecar_path(id)).await?;
+        Ok(existed)
+    }
+
+    async fn soft_delete(&self, id: Ulid) -> Result<bool, StorageError> {
+        self.set_deleted_at(id, Some(Utc::now())).await
+    }
+
+    async fn restore(&self, id: Ulid) -> Result<bool, StorageError> {
+        self.set_deleted_at(id, None).await
+    }
+
+    async fn extend_expiry(
+        &self,
+        id: Ulid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, StorageError> {
+        let Some(mut sidecar) = self.sidecar(id).await? else {
+            return Ok(false);
+        };
+        match sidecar.expires_at {
+            Some(current) if current < expires_at => sidecar.expires_at = Some(expires_at),
+            Some(_) => return Ok(true),
+            None => return Ok(false),
+        }
+        write_atomic(&self.sidecar_path(id), &serde_json::to_vec(&sidecar)?).await?;
+        Ok(true)
+    }
+
+    async fn list(
+        &self,
+        after: Option<Ulid>,
+        limit: usize,
+    ) -> Result<Vec<(Ulid, Metadata)>, StorageError> {
+        let mut ids = self.ids().await?;
+        ids.retain(|id| after.is_none_or(|after| *id > after));
+        ids.sort_unstable();
+
+        let mut entries = Vec::new();
+        for id in ids {
+            if entries.len() >= limit {
+                break;
+            }
+            if let Some(metadata) = self.metadata(id).await? {
+                entries.push((id, metadata));
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn total_length(&self) -> Result<u64, StorageError> {
+        let mut length = 0;
+        for id in self.ids().await? {
+            match fs::metadata(self.value_path(id)).await {
+                Ok(metadata) => length += metadata.len(),
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(length)
+    }
+
+    async fn evict(&self, max: u64) -> Result<u64, StorageError> {
+        let mut accessed = Vec::new();
+        for id in self.ids().await? {
+            if let Some(sidecar) = self.sidecar(id).await? {
+                let created_at = sidecar.created_at.unwrap_or_else(|| ulid_time(id));
+                accessed.push((sidecar.accessed_at.unwrap_or(created_at), id));
+            }
+        }
+        // most recently accessed first
+        accessed.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut evicted = 0;
+        for &(_, id) in accessed.iter().skip(max as usize) {
+            self.delete(id).await?;
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+
+    async fn sweep(&self, deleted_before: DateTime<Utc>) -> Result<u64, StorageError> {
+        if let Err(err) = self.sweep_staging().await {
+            warn!("failed to sweep staged values: {err}");
+        }
+        let mut swept = 0;
+        for id in self.ids().await? {
+            match self.read_sidecar(id).await {
+                Ok(Some(sidecar))
+                    if sidecar.expired()
+                        || sidecar
+                            .deleted_at
+                            .is_some_and(|deleted_at| deleted_at < deleted_before) =>
+                {
+                    self.delete(id).await?;
+                    swept += 1;
+                }
+                Ok(_) => {}
+                Err(err) => warn!("skipping {id} while sweeping: {err}"),
+            }
+        }
+        Ok(swept)
+    }
+
+    async fn health(&self) -> Result<(), StorageError> {
+        fs::metadata(&self.dir).await?;
+        Ok(())
+    }
+}
+
+/// a value being written to `<dir>/staging/<ulid>`
+#[derive(Debug)]
+struct FsStaged {
+    storage: FsStorage,
+    path: PathBuf,
+    file: File,
+}
+
+#[async_trait]
+impl Staged for FsStaged {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), StorageError> {
+        Ok(self.file.write_all(chunk).await?)
+    }
+
+    async fn commit(self: Box<Self>, id: Ulid, entry: NewEntry<'_>) -> Result<bool, StorageError> {
+        let Self {
+            storage,
+            path,
+            mut file,
+        } = *self;
+        // tokio finishes writes in the background, wait for them before moving the file
+        file.flush().await?;
+        drop(file);
+        if !storage.create_sidecar(id, &entry).await? {
+            remove(&path).await?;
+            return Ok(false);
+        }
+        fs::rename(&path, storage.value_path(id)).await?;
+        if entry.password_hash.is_none() {
+            storage.write_hash(id, entry.hash).await?;
+        }
+        Ok(true)
+    }
+
+    async fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        drop(self.file);
+        remove(&self.path).await?;
+        Ok(())
+    }
+}
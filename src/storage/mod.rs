@@ -0,0 +1,362 @@
+mod fs;
+mod postgres;
+mod s3;
+
+use std::fmt::Debug;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream};
+use sqlx::types::chrono::{DateTime, Utc};
+use thiserror::Error;
+use ulid::Ulid;
+
+use crate::encoding::{Encoding, UnknownEncoding};
+
+pub use fs::FsStorage;
+pub use postgres::PgStorage;
+pub use s3::S3Storage;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("error while contacting database: {0}")]
+    Postgres(#[from] sqlx::Error),
+    #[error("error while accessing data directory: {0}")]
+    Io(#[from] io::Error),
+    #[error("entry is corrupt: {0}")]
+    UnknownEncoding(#[from] UnknownEncoding),
+    #[error("entry is corrupt: {0}")]
+    Metadata(#[from] serde_json::Error),
+    #[error("error while contacting object storage: {0}")]
+    S3(Box<aws_sdk_s3::Error>),
+    #[error("entry is corrupt: missing or invalid `{0}` metadata")]
+    ObjectMetadata(&'static str),
+    #[error("entry was replaced or deleted while it was being read")]
+    Changed,
+}
+
+impl StorageError {
+    /// whether the database is down or restarting, rather than the query failing
+    pub fn is_connection_error(&self) -> bool {
+        matches!(self, Self::Postgres(err) if postgres::is_connection_error(err))
+    }
+}
+
+/// the chunks of a value, as read from storage
+pub type ValueStream = BoxStream<'static, Result<Vec<u8>, StorageError>>;
+
+/// everything about an entry about to be stored but its value
+#[derive(Debug)]
+pub struct NewEntry<'a> {
+    /// what the value is compressed with
+    pub encoding: Encoding,
+    /// id of the zstd dictionary the value was compressed with
+    pub dictionary_id: Option<u32>,
+    /// whether the value was encrypted after compressing it
+    pub encrypted: bool,
+    /// argon2 hash of the password needed to retrieve the entry, in the phc string format
+    pub password_hash: Option<&'a str>,
+    /// length of the uncompressed value
+    pub size: u64,
+    pub content_type: Option<&'a str>,
+    /// sha-256 of the uncompressed value
+    pub hash: &'a [u8],
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// the uncompressed value compressed with gzip, encrypted if the value is,
+    /// ignored by backends that can't serve it
+    pub gzip: Option<&'a [u8]>,
+}
+
+/// a new value for an existing entry, which keeps its password, expiry and creation time
+#[derive(Debug)]
+pub struct Replacement<'a> {
+    pub encoding: Encoding,
+    pub dictionary_id: Option<u32>,
+    pub encrypted: bool,
+    pub size: u64,
+    /// `None` keeps the content type the entry already has
+    pub content_type: Option<&'a str>,
+    pub hash: &'a [u8],
+    pub modified_at: DateTime<Utc>,
+    /// like `NewEntry::gzip`, an old one is dropped if this is `None`
+    pub gzip: Option<&'a [u8]>,
+}
+
+/// everything about a stored entry but its value
+#[derive(Debug)]
+pub struct Metadata {
+    pub encoding: Encoding,
+    pub dictionary_id: Option<u32>,
+    pub encrypted: bool,
+    pub password_hash: Option<String>,
+    /// length of the stored value
+    pub length: u64,
+    /// length of the uncompressed value, `None` for compressed entries from before it was recorded
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    /// hex encoded sha-256 of the uncompressed value, `None` for entries from before it was recorded
+    pub hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// when the value was last replaced, `None` if it never was
+    pub modified_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// times the entry was retrieved, `None` if the backend doesn't count them
+    pub views: Option<u64>,
+}
+
+/// aggregates over all entries, to see how storage is used at a glance
+#[derive(Default, Debug)]
+pub struct Stats {
+    pub count: u64,
+    /// sum of the lengths of the stored values, and of their precomputed gzip
+    pub total_length: u64,
+    /// `None` if there are no entries
+    pub average_length: Option<f64>,
+    pub median_length: Option<f64>,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+}
+
+impl Metadata {
+    /// when the value last changed, for `Last-Modified`
+    pub fn last_modified(&self) -> DateTime<Utc> {
+        self.modified_at.unwrap_or(self.created_at)
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    /// the value, compressed with `metadata.encoding`
+    pub value: Vec<u8>,
+    pub metadata: Metadata,
+}
+
+/// where entries are kept
+///
+/// expired and soft-deleted entries must be treated as if they don't exist, even
+/// before they are swept.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// stores an entry, returning `false` without storing it if there already is one at `id`
+    async fn put(&self, id: Ulid, value: &[u8], entry: NewEntry<'_>) -> Result<bool, StorageError>;
+
+    /// starts an entry whose value is written in chunks, `None` if that is not supported
+    async fn stage(&self) -> Result<Option<Box<dyn Staged>>, StorageError> {
+        Ok(None)
+    }
+
+    /// finds an entry with the same hash and content type that lives at least until `expires_at`
+    ///
+    /// entries with a password are never returned.
+    async fn find_duplicate(
+        &self,
+        hash: &[u8],
+        content_type: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Option<Ulid>, StorageError>;
+
+    async fn get(&self, id: Ulid) -> Result<Option<Entry>, StorageError>;
+
+    /// like `get`, with the value read in chunks as the stream is polled
+    ///
+    /// backends that can't read values in parts read them whole.
+    async fn get_stream(&self, id: Ulid) -> Result<Option<(Metadata, ValueStream)>, StorageError> {
+        let Some(Entry { value, metadata }) = self.get(id).await? else {
+            return Ok(None);
+        };
+        let value: ValueStream =
+            Box::pin(stream::once(async move { Ok::<_, StorageError>(value) }));
+        Ok(Some((metadata, value)))
+    }
+
+    /// like `get`, but with the precomputed gzip of the value in place of the
+    /// value if there is one, as if it was stored with gzip
+    async fn get_gzip(&self, id: Ulid) -> Result<Option<Entry>, StorageError> {
+        self.get(id).await
+    }
+
+    /// like `get` for several entries at once, leaving out the ones that don't exist
+    async fn get_many(&self, ids: &[Ulid]) -> Result<Vec<(Ulid, Entry)>, StorageError> {
+        let mut entries = Vec::with_capacity(ids.len());
+        for &id in ids {
+            if let Some(entry) = self.get(id).await? {
+                entries.push((id, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// like `get`, without loading the value
+    async fn metadata(&self, id: Ulid) -> Result<Option<Metadata>, StorageError>;
+
+    /// whether there is an entry at `id`, without loading its value or metadata if possible
+    async fn exists(&self, id: Ulid) -> Result<bool, StorageError> {
+        Ok(self.metadata(id).await?.is_some())
+    }
+
+    /// the entry `ID_MODE=short` gave `slug`, which only postgres stores
+    async fn resolve_slug(&self, _slug: &str) -> Result<Option<Ulid>, StorageError> {
+        Ok(None)
+    }
+
+    /// gives an entry `slug` unless it has one already, returning the slug it
+    /// has afterwards, or `None` if `slug` is taken or there is no entry at `id`
+    async fn assign_slug(&self, _id: Ulid, _slug: &str) -> Result<Option<String>, StorageError> {
+        Ok(None)
+    }
+
+    /// adds to the view counts of entries, which are counted in memory and added in batches
+    ///
+    /// this is also when entries are marked as accessed for `evict`.
+    async fn add_views(&self, _views: &[(Ulid, u64)]) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// replaces the value of an entry, returning whether there was one to replace
+    async fn replace(
+        &self,
+        id: Ulid,
+        value: &[u8],
+        replacement: Replacement<'_>,
+    ) -> Result<bool, StorageError>;
+
+    /// returns whether the entry existed
+    async fn delete(&self, id: Ulid) -> Result<bool, StorageError>;
+
+    /// marks an entry as deleted, so it can still be restored until it is swept
+    ///
+    /// returns whether the entry existed and wasn't already soft-deleted.
+    async fn soft_delete(&self, id: Ulid) -> Result<bool, StorageError>;
+
+    /// undoes `soft_delete`, returning whether there was a soft-deleted entry
+    async fn restore(&self, id: Ulid) -> Result<bool, StorageError>;
+
+    /// pushes the expiry of an entry back to `expires_at`, returning whether
+    /// there was one that expires but hasn't yet
+    ///
+    /// entries that never expire are left alone, and expiries are never moved earlier.
+    async fn extend_expiry(
+        &self,
+        id: Ulid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, StorageError>;
+
+    /// lists up to `limit` entries with ids after `after`, ordered by id
+    ///
+    /// that is the order entries were created in, except for ones put at an id
+    /// given by the client or imported with the id they had elsewhere.
+    async fn list(
+        &self,
+        after: Option<Ulid>,
+        limit: usize,
+    ) -> Result<Vec<(Ulid, Metadata)>, StorageError>;
+
+    /// aggregates over the entries `list` would return, paging through them by default
+    async fn stats(&self) -> Result<Stats, StorageError> {
+        let mut lengths = Vec::new();
+        let mut stats = Stats::default();
+        let mut after = None;
+        loop {
+            let page = self.list(after, STATS_PAGE_SIZE).await?;
+            for (_, metadata) in &page {
+                lengths.push(metadata.length);
+                let created_at = metadata.created_at;
+                stats.oldest = Some(
+                    stats
+                        .oldest
+                        .map_or(created_at, |oldest| oldest.min(created_at)),
+                );
+                stats.newest = stats.newest.max(Some(created_at));
+            }
+            match page.last() {
+                Some(&(id, _)) if page.len() == STATS_PAGE_SIZE => after = Some(id),
+                _ => break,
+            }
+        }
+
+        lengths.sort_unstable();
+        stats.count = lengths.len() as u64;
+        stats.total_length = lengths.iter().sum();
+        if let Some(&middle) = lengths.get(lengths.len() / 2) {
+            stats.average_length = Some(stats.total_length as f64 / lengths.len() as f64);
+            stats.median_length = Some(if lengths.len() % 2 == 0 {
+                (lengths[lengths.len() / 2 - 1] + middle) as f64 / 2.0
+            } else {
+                middle as f64
+            });
+        }
+        Ok(stats)
+    }
+
+    /// deletes the least recently retrieved entries until at most `max` are left,
+    /// returning how many were deleted
+    ///
+    /// backends that don't keep track of retrievals delete the oldest entries instead.
+    async fn evict(&self, max: u64) -> Result<u64, StorageError>;
+
+    /// the sum of the lengths of all stored values, including ones not swept yet
+    async fn total_length(&self) -> Result<u64, StorageError>;
+
+    /// deletes expired entries and entries soft-deleted before `deleted_before`,
+    /// returning how many were deleted
+    async fn sweep(&self, deleted_before: DateTime<Utc>) -> Result<u64, StorageError>;
+
+    /// checks that the storage is reachable
+    async fn health(&self) -> Result<(), StorageError>;
+}
+
+/// entries listed at once by the default `Storage::stats`
+const STATS_PAGE_SIZE: usize = 1000;
+
+/// how long a staged entry can go without writes before it is swept
+const ABANDONED_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// an entry whose value is being written, which doesn't exist until it is committed
+///
+/// staged entries that are neither committed nor aborted are cleaned up when sweeping.
+#[async_trait]
+pub trait Staged: Send {
+    /// appends to the value, compressed with the encoding that will be passed to `commit`
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), StorageError>;
+
+    /// stores the entry like `Storage::put`, with everything written as its value
+    async fn commit(self: Box<Self>, id: Ulid, entry: NewEntry<'_>) -> Result<bool, StorageError>;
+
+    async fn abort(self: Box<Self>) -> Result<(), StorageError>;
+}
+
+#[derive(Error, Debug)]
+#[error("unknown storage backend `{0}`")]
+pub struct UnknownBackend(String);
+
+/// the creation time encoded in a ulid, for entries from before it was recorded
+fn ulid_time(id: Ulid) -> DateTime<Utc> {
+    id.datetime().into()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    Postgres,
+    Fs,
+    S3,
+}
+
+impl FromStr for Backend {
+    type Err = UnknownBackend;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(Self::Postgres),
+            "fs" => Ok(Self::Fs),
+            "s3" => Ok(Self::S3),
+            _ => Err(UnknownBackend(s.to_string())),
+        }
+    }
+}
@@ -0,0 +1,752 @@
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::TimeDelta;
+use futures_util::StreamExt;
+use futures_util::stream;
+use sqlx::PgPool;
+use sqlx::types::Uuid;
+use sqlx::types::chrono::{DateTime, Utc};
+use tracing::warn;
+use ulid::Ulid;
+
+use super::{
+    ABANDONED_AFTER, Entry, Metadata, NewEntry, Replacement, Staged, Stats, Storage, StorageError,
+    ValueStream, hex, ulid_time,
+};
+use crate::encoding::Encoding;
+
+const UPLOAD: &str = "INSERT INTO entries \
+    (id, value, expires_at, encoding, size, content_type, hash, created_at, encrypted, password_hash, \
+    dictionary_id, gzip) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)";
+const FIND_DUPLICATE: &str = "SELECT id FROM entries \
+    WHERE hash=$1 AND content_type IS NOT DISTINCT FROM $2 AND password_hash IS NULL \
+    AND deleted_at IS NULL \
+    AND (expires_at IS NULL OR ($3::TIMESTAMPTZ IS NOT NULL AND expires_at >= $3)) \
+    LIMIT 1";
+const RETRIEVE: &str = "SELECT value, \
+    octet_length(value)::BIGINT AS length, size, encoding, content_type, created_at, views, encrypted, password_hash, \
+    dictionary_id, hash, modified_at, expires_at \
+    FROM entries \
+    WHERE id=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+const RETRIEVE_GZIP: &str = "SELECT COALESCE(gzip, value) AS value, \
+    octet_length(COALESCE(gzip, value))::BIGINT AS length, size, \
+    CASE WHEN gzip IS NULL THEN encoding ELSE 'gzip' END AS encoding, \
+    content_type, created_at, views, encrypted, password_hash, \
+    CASE WHEN gzip IS NULL THEN dictionary_id END AS dictionary_id, hash, modified_at, expires_at \
+    FROM entries \
+    WHERE id=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+/// the first chunk of the value with the metadata, the rest is read with `RETRIEVE_CHUNK`
+const RETRIEVE_FIRST_CHUNK: &str = "SELECT substring(value FROM 1 FOR $2) AS value, \
+    octet_length(value)::BIGINT AS length, size, encoding, content_type, created_at, views, encrypted, password_hash, \
+    dictionary_id, hash, modified_at, expires_at \
+    FROM entries \
+    WHERE id=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+/// finds nothing if the entry was replaced since the first chunk, so chunks are never mixed up
+const RETRIEVE_CHUNK: &str = "SELECT substring(value FROM $2 FOR $3) FROM entries \
+    WHERE id=$1 AND modified_at IS NOT DISTINCT FROM $4 AND deleted_at IS NULL";
+const RETRIEVE_MANY: &str = "SELECT id, value, \
+    octet_length(value)::BIGINT AS length, size, encoding, content_type, created_at, views, encrypted, password_hash, \
+    dictionary_id, hash, modified_at, expires_at \
+    FROM entries \
+    WHERE id = ANY($1) AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+const METADATA: &str = "SELECT \
+    octet_length(value)::BIGINT AS length, size, encoding, content_type, created_at, views, encrypted, password_hash, \
+    dictionary_id, hash, modified_at, expires_at \
+    FROM entries \
+    WHERE id=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+const LIST: &str = "SELECT id, \
+    octet_length(value)::BIGINT AS length, size, encoding, content_type, created_at, views, encrypted, password_hash, \
+    dictionary_id, hash, modified_at, expires_at \
+    FROM entries \
+    WHERE ($1::UUID IS NULL OR id > $1) AND (expires_at IS NULL OR expires_at > now()) \
+    AND deleted_at IS NULL \
+    ORDER BY id LIMIT $2";
+const EXISTS: &str = "SELECT EXISTS(SELECT 1 FROM entries \
+    WHERE id=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL)";
+const RESOLVE_SLUG: &str = "SELECT id FROM entries \
+    WHERE slug=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+const ASSIGN_SLUG: &str = "UPDATE entries SET slug = COALESCE(slug, $2) WHERE id=$1 RETURNING slug";
+const ADD_VIEWS: &str = "UPDATE entries SET views = views + v.views, accessed_at = now() \
+    FROM UNNEST($1::UUID[], $2::BIGINT[]) AS v(id, views) WHERE entries.id = v.id";
+const REPLACE: &str = "UPDATE entries SET value=$2, encoding=$3, dictionary_id=$4, encrypted=$5, \
+    size=$6, content_type=COALESCE($7, content_type), hash=$8, modified_at=$9, gzip=$10 \
+    WHERE id=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+const DELETE: &str = "DELETE FROM entries WHERE id=$1";
+const SOFT_DELETE: &str = "UPDATE entries SET deleted_at = now() \
+    WHERE id=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+const RESTORE: &str = "UPDATE entries SET deleted_at = NULL \
+    WHERE id=$1 AND (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NOT NULL";
+const EXTEND_EXPIRY: &str = "UPDATE entries SET expires_at = GREATEST(expires_at, $2) \
+    WHERE id=$1 AND expires_at > now() AND deleted_at IS NULL";
+const HEALTH: &str = "SELECT 1";
+const TOTAL_LENGTH: &str = "SELECT \
+    COALESCE(SUM(octet_length(value) + COALESCE(octet_length(gzip), 0)), 0)::BIGINT FROM entries";
+/// only looks at lengths and timestamps, never at the values themselves
+const STATS: &str = "SELECT COUNT(*) AS count, \
+    COALESCE(SUM(octet_length(value) + COALESCE(octet_length(gzip), 0)), 0)::BIGINT AS total_length, \
+    AVG(octet_length(value))::FLOAT8 AS average_length, \
+    percentile_cont(0.5) WITHIN GROUP (ORDER BY octet_length(value)) AS median_length, \
+    MIN(created_at) AS oldest, MAX(created_at) AS newest \
+    FROM entries \
+    WHERE (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL";
+/// expired and soft-deleted entries neither count nor get evicted, they are left to `SWEEP`
+const EVICT: &str = "DELETE FROM entries WHERE id IN (SELECT id FROM entries \
+    WHERE (expires_at IS NULL OR expires_at > now()) AND deleted_at IS NULL \
+    ORDER BY COALESCE(accessed_at, created_at) DESC, id DESC OFFSET $1)";
+const SWEEP: &str = "DELETE FROM entries WHERE expires_at < now() OR deleted_at < $1";
+const STAGE: &str = "INSERT INTO staged (id) VALUES ($1)";
+const STAGE_WRITE: &str = "WITH staged AS \
+    (UPDATE staged SET updated_at = now() WHERE id=$1 RETURNING id) \
+    INSERT INTO staged_chunks (id, seq, value) SELECT id, $2, $3 FROM staged";
+/// the chunks are only put together once, here
+const STAGE_COMMIT: &str = "INSERT INTO entries \
+    (id, value, expires_at, encoding, size, content_type, hash, created_at, encrypted, password_hash, \
+    dictionary_id) \
+    SELECT $1, \
+    COALESCE((SELECT string_agg(value, '' ORDER BY seq) FROM staged_chunks WHERE id=$2), ''), \
+    $3, $4, $5, $6, $7, $8, $9, $10, $11 FROM staged WHERE id=$2";
+/// takes the chunks with it
+const STAGE_ABORT: &str = "DELETE FROM staged WHERE id=$1";
+const SWEEP_STAGED: &str =
+    "DELETE FROM staged WHERE updated_at < now() - make_interval(secs => $1)";
+
+#[derive(sqlx::FromRow, Debug)]
+struct MetadataRow {
+    length: i64,
+    size: Option<i64>,
+    encoding: Option<String>,
+    content_type: Option<String>,
+    created_at: DateTime<Utc>,
+    views: i64,
+    encrypted: bool,
+    password_hash: Option<String>,
+    dictionary_id: Option<i64>,
+    hash: Option<Vec<u8>>,
+    modified_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<MetadataRow> for Metadata {
+    type Error = StorageError;
+
+    fn try_from(row: MetadataRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            encoding: Encoding::from_column(row.encoding.as_deref())?,
+            dictionary_id: row.dictionary_id.map(|id| id as u32),
+            hash: row.hash.as_deref().map(hex),
+            encrypted: row.encrypted,
+            password_hash: row.password_hash,
+            length: row.length as u64,
+            size: row.size.map(|size| size as u64),
+            content_type: row.content_type,
+            created_at: row.created_at,
+            modified_at: row.modified_at,
+            expires_at: row.expires_at,
+            views: Some(row.views as u64),
+        })
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct EntryRow {
+    value: Vec<u8>,
+    #[sqlx(flatten)]
+    metadata: MetadataRow,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct BatchRow {
+    id: Uuid,
+    value: Vec<u8>,
+    #[sqlx(flatten)]
+    metadata: MetadataRow,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct StatsRow {
+    count: i64,
+    total_length: i64,
+    average_length: Option<f64>,
+    median_length: Option<f64>,
+    oldest: Option<DateTime<Utc>>,
+    newest: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct ListRow {
+    id: Uuid,
+    #[sqlx(flatten)]
+    metadata: MetadataRow,
+}
+
+/// how much of a value `get_stream` reads at once
+const STREAM_CHUNK: i64 = 1024 * 1024;
+
+/// backoff before the first retry, doubled for each one after
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+
+/// whether retrying might make `err` go away
+fn is_transient(err: &sqlx::Error) -> bool {
+    matches!(err, sqlx::Error::PoolTimedOut) || is_connection_error(err)
+}
+
+/// whether `err` means the database can't be reached right now
+pub(super) fn is_connection_error(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_) => true,
+        // connection exceptions and the server shutting down
+        sqlx::Error::Database(err) => err
+            .code()
+            .is_some_and(|code| code.starts_with("08") || code.starts_with("57P0")),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PgStorage {
+    pool: PgPool,
+    /// where reads go instead of `pool`, if set
+    replica: Option<PgPool>,
+    /// how far the replica can be behind, entries it doesn't have are only
+    /// looked up on the primary if they are newer than that
+    replica_lag: TimeDelta,
+    /// unix time in milliseconds a slug was last assigned at, for the same with slugs
+    slug_assigned_at: Arc<AtomicI64>,
+    /// how many times to try queries that fail with transient errors
+    attempts: NonZeroU32,
+}
+
+impl PgStorage {
+    pub fn new(pool: PgPool, attempts: NonZeroU32) -> Self {
+        Self {
+            pool,
+            replica: None,
+            replica_lag: TimeDelta::zero(),
+            slug_assigned_at: Arc::default(),
+            attempts,
+        }
+    }
+
+    /// sends reads of entries to `replica`, which can be up to `lag` behind,
+    /// and everything else to the primary
+    pub fn with_replica(self, replica: PgPool, lag: TimeDelta) -> Self {
+        Self {
+            replica: Some(replica),
+            replica_lag: lag,
+            ..self
+        }
+    }
+
+    /// runs `read` on the replica, and again on the primary if it found nothing
+    /// and the replica may not have caught up with the write yet, `lagging`
+    ///
+    /// misses that can't be explained by lag stay on the replica, or looking
+    /// up ids that don't exist would put the load back on the primary.
+    async fn read<T, F, Fut>(&self, lagging: bool, read: F) -> Result<Option<T>, sqlx::Error>
+    where
+        F: Fn(PgPool) -> Fut,
+        Fut: Future<Output = Result<Option<T>, sqlx::Error>>,
+    {
+        if let Some(replica) = &self.replica {
+            let found = self.retry(|| read(replica.clone())).await?;
+            if found.is_some() || !lagging {
+                return Ok(found);
+            }
+        }
+        self.retry(|| read(self.pool.clone())).await
+    }
+
+    /// whether the replica may not have `id` yet, going by when its ulid was made
+    fn may_lag(&self, id: Ulid) -> bool {
+        ulid_time(id) > Utc::now() - self.replica_lag
+    }
+
+    /// whether the replica may not have a slug yet, going by when one was last assigned
+    fn slugs_may_lag(&self) -> bool {
+        Utc::now().timestamp_millis() - self.slug_assigned_at.load(Ordering::Relaxed)
+            < self.replica_lag.num_milliseconds()
+    }
+
+    /// runs `query`, retrying with exponential backoff on transient errors
+    async fn retry<T, F, Fut>(&self, mut query: F) -> Result<T, sqlx::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1.. {
+            match query().await {
+                Err(err) if attempt < self.attempts.get() && is_transient(&err) => {
+                    warn!(
+                        "database query failed (attempt {attempt}/{}), retrying in {}ms: {err}",
+                        self.attempts,
+                        backoff.as_millis()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        unreachable!("ran out of attempts")
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn put(&self, id: Ulid, value: &[u8], entry: NewEntry<'_>) -> Result<bool, StorageError> {
+        let result = self
+            .retry(|| {
+                sqlx::query(UPLOAD)
+                    .bind(Uuid::from(id))
+                    .bind(value)
+                    .bind(entry.expires_at)
+                    .bind(entry.encoding.to_column())
+                    .bind(entry.size as i64)
+                    .bind(entry.content_type)
+                    .bind(entry.hash)
+                    .bind(entry.created_at)
+                    .bind(entry.encrypted)
+                    .bind(entry.password_hash)
+                    .bind(entry.dictionary_id.map(i64::from))
+                    .bind(entry.gzip)
+                    .execute(&self.pool)
+            })
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn find_duplicate(
+        &self,
+        hash: &[u8],
+        content_type: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Option<Ulid>, StorageError> {
+        let id: Option<Uuid> = self
+            .retry(|| {
+                sqlx::query_scalar(FIND_DUPLICATE)
+                    .bind(hash)
+                    .bind(content_type)
+                    .bind(expires_at)
+                    .fetch_optional(&self.pool)
+            })
+            .await?;
+        Ok(id.map(Ulid::from))
+    }
+
+    async fn get(&self, id: Ulid) -> Result<Option<Entry>, StorageError> {
+        let row: Option<EntryRow> = self
+            .read(self.may_lag(id), |pool| async move {
+                sqlx::query_as(RETRIEVE)
+                    .bind(Uuid::from(id))
+                    .fetch_optional(&pool)
+                    .await
+            })
+            .await?;
+        let Some(EntryRow { value, metadata }) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Entry {
+            value,
+            metadata: metadata.try_into()?,
+        }))
+    }
+
+    async fn get_stream(&self, id: Ulid) -> Result<Option<(Metadata, ValueStream)>, StorageError> {
+        // the rest is read from wherever the first chunk was
+        let row: Option<(EntryRow, PgPool)> = self
+            .read(self.may_lag(id), |pool| async move {
+                let row = sqlx::query_as(RETRIEVE_FIRST_CHUNK)
+                    .bind(Uuid::from(id))
+                    .bind(STREAM_CHUNK as i32)
+                    .fetch_optional(&pool)
+                    .await?;
+                Ok::<_, sqlx::Error>(row.map(|row| (row, pool)))
+            })
+            .await?;
+        let Some((EntryRow { value, metadata }, pool)) = row else {
+            return Ok(None);
+        };
+        let metadata = Metadata::try_from(metadata)?;
+
+        let length = metadata.length as i64;
+        let modified_at = metadata.modified_at;
+        // offsets are 1-based, like `substring`
+        let rest = stream::try_unfold(value.len() as i64 + 1, move |offset| {
+            let pool = pool.clone();
+            async move {
+                if offset > length {
+                    return Ok::<_, StorageError>(None);
+                }
+                let chunk = read_chunk(&pool, id, offset, modified_at).await?;
+                let next = offset + chunk.len() as i64;
+                Ok(Some((chunk, next)))
+            }
+        });
+        let value: ValueStream =
+            Box::pin(stream::once(async move { Ok::<_, StorageError>(value) }).chain(rest));
+        Ok(Some((metadata, value)))
+    }
+
+    async fn get_gzip(&self, id: Ulid) -> Result<Option<Entry>, StorageError> {
+        let row: Option<EntryRow> = self
+            .read(self.may_lag(id), |pool| async move {
+                sqlx::query_as(RETRIEVE_GZIP)
+                    .bind(Uuid::from(id))
+                    .fetch_optional(&pool)
+                    .await
+            })
+            .await?;
+        let Some(EntryRow { value, metadata }) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Entry {
+            value,
+            metadata: metadata.try_into()?,
+        }))
+    }
+
+    async fn get_many(&self, ids: &[Ulid]) -> Result<Vec<(Ulid, Entry)>, StorageError> {
+        let ids: Vec<_> = ids.iter().copied().map(Uuid::from).collect();
+        let mut rows: Vec<BatchRow> = self
+            .retry(|| {
+                sqlx::query_as(RETRIEVE_MANY)
+                    .bind(&ids)
+                    .fetch_all(self.replica.as_ref().unwrap_or(&self.pool))
+            })
+            .await?;
+        // like `read`, for the ones that may only just have been written
+        let lagging: Vec<_> = ids
+            .iter()
+            .filter(|&&id| self.may_lag(Ulid::from(id)) && !rows.iter().any(|row| row.id == id))
+            .copied()
+            .collect();
+        if self.replica.is_some() && !lagging.is_empty() {
+            let found: Vec<BatchRow> = self
+                .retry(|| {
+                    sqlx::query_as(RETRIEVE_MANY)
+                        .bind(&lagging)
+                        .fetch_all(&self.pool)
+                })
+                .await?;
+            rows.extend(found);
+        }
+        rows.into_iter()
+            .map(
+                |BatchRow {
+                     id,
+                     value,
+                     metadata,
+                 }| {
+                    Ok((
+                        Ulid::from(id),
+                        Entry {
+                            value,
+                            metadata: metadata.try_into()?,
+                        },
+                    ))
+                },
+            )
+            .collect()
+    }
+
+    async fn metadata(&self, id: Ulid) -> Result<Option<Metadata>, StorageError> {
+        let row: Option<MetadataRow> = self
+            .read(self.may_lag(id), |pool| async move {
+                sqlx::query_as(METADATA)
+                    .bind(Uuid::from(id))
+                    .fetch_optional(&pool)
+                    .await
+            })
+            .await?;
+        row.map(Metadata::try_from).transpose()
+    }
+
+    async fn exists(&self, id: Ulid) -> Result<bool, StorageError> {
+        let exists = self
+            .read(self.may_lag(id), |pool| async move {
+                let exists: bool = sqlx::query_scalar(EXISTS)
+                    .bind(Uuid::from(id))
+                    .fetch_one(&pool)
+                    .await?;
+                Ok::<_, sqlx::Error>(exists.then_some(()))
+            })
+            .await?;
+        Ok(exists.is_some())
+    }
+
+    async fn resolve_slug(&self, slug: &str) -> Result<Option<Ulid>, StorageError> {
+        let id: Option<Uuid> = self
+            .read(self.slugs_may_lag(), |pool| async move {
+                sqlx::query_scalar(RESOLVE_SLUG)
+                    .bind(slug)
+                    .fetch_optional(&pool)
+                    .await
+            })
+            .await?;
+        Ok(id.map(Ulid::from))
+    }
+
+    async fn assign_slug(&self, id: Ulid, slug: &str) -> Result<Option<String>, StorageError> {
+        let result = self
+            .retry(|| {
+                sqlx::query_scalar(ASSIGN_SLUG)
+                    .bind(Uuid::from(id))
+                    .bind(slug)
+                    .fetch_optional(&self.pool)
+            })
+            .await;
+        match result {
+            Ok(slug) => {
+                self.slug_assigned_at
+                    .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+                Ok(slug)
+            }
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn add_views(&self, views: &[(Ulid, u64)]) -> Result<(), StorageError> {
+        let (ids, counts): (Vec<_>, Vec<_>) = views
+            .iter()
+            .map(|&(id, count)| (Uuid::from(id), count as i64))
+            .unzip();
+        sqlx::query(ADD_VIEWS)
+            .bind(ids)
+            .bind(counts)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn replace(
+        &self,
+        id: Ulid,
+        value: &[u8],
+        replacement: Replacement<'_>,
+    ) -> Result<bool, StorageError> {
+        let result = self
+            .retry(|| {
+                sqlx::query(REPLACE)
+                    .bind(Uuid::from(id))
+                    .bind(value)
+                    .bind(replacement.encoding.to_column())
+                    .bind(replacement.dictionary_id.map(i64::from))
+                    .bind(replacement.encrypted)
+                    .bind(replacement.size as i64)
+                    .bind(replacement.content_type)
+                    .bind(replacement.hash)
+                    .bind(replacement.modified_at)
+                    .bind(replacement.gzip)
+                    .execute(&self.pool)
+            })
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete(&self, id: Ulid) -> Result<bool, StorageError> {
+        let result = sqlx::query(DELETE)
+            .bind(Uuid::from(id))
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn soft_delete(&self, id: Ulid) -> Result<bool, StorageError> {
+        let result = sqlx::query(SOFT_DELETE)
+            .bind(Uuid::from(id))
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn restore(&self, id: Ulid) -> Result<bool, StorageError> {
+        let result = sqlx::query(RESTORE)
+            .bind(Uuid::from(id))
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn extend_expiry(
+        &self,
+        id: Ulid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, StorageError> {
+        let result = sqlx::query(EXTEND_EXPIRY)
+            .bind(Uuid::from(id))
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn list(
+        &self,
+        after: Option<Ulid>,
+        limit: usize,
+    ) -> Result<Vec<(Ulid, Metadata)>, StorageError> {
+        let rows: Vec<ListRow> = sqlx::query_as(LIST)
+            .bind(after.map(Uuid::from))
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter()
+            .map(|ListRow { id, metadata }| {
+                Metadata::try_from(metadata).map(|metadata| (Ulid::from(id), metadata))
+            })
+            .collect()
+    }
+
+    async fn stats(&self) -> Result<Stats, StorageError> {
+        let row: StatsRow = self
+            .retry(|| sqlx::query_as(STATS).fetch_one(&self.pool))
+            .await?;
+        Ok(Stats {
+            count: row.count as u64,
+            total_length: row.total_length as u64,
+            average_length: row.average_length,
+            median_length: row.median_length,
+            oldest: row.oldest,
+            newest: row.newest,
+        })
+    }
+
+    async fn stage(&self) -> Result<Option<Box<dyn Staged>>, StorageError> {
+        let id = Uuid::from(Ulid::new());
+        self.retry(|| sqlx::query(STAGE).bind(id).execute(&self.pool))
+            .await?;
+        Ok(Some(Box::new(PgStaged {
+            pool: self.pool.clone(),
+            id,
+            chunks: 0,
+        })))
+    }
+
+    async fn total_length(&self) -> Result<u64, StorageError> {
+        let length: i64 = sqlx::query_scalar(TOTAL_LENGTH)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(length as u64)
+    }
+
+    async fn evict(&self, max: u64) -> Result<u64, StorageError> {
+        let result = sqlx::query(EVICT)
+            .bind(max as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn sweep(&self, deleted_before: DateTime<Utc>) -> Result<u64, StorageError> {
+        sqlx::query(SWEEP_STAGED)
+            .bind(ABANDONED_AFTER.as_secs_f64())
+            .execute(&self.pool)
+            .await?;
+        let result = sqlx::query(SWEEP)
+            .bind(deleted_before)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn health(&self) -> Result<(), StorageError> {
+        sqlx::query(HEALTH).execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+/// the chunk of a value starting at `offset`, for `get_stream`
+async fn read_chunk(
+    pool: &PgPool,
+    id: Ulid,
+    offset: i64,
+    modified_at: Option<DateTime<Utc>>,
+) -> Result<Vec<u8>, StorageError> {
+    let chunk: Option<Vec<u8>> = sqlx::query_scalar(RETRIEVE_CHUNK)
+        .bind(Uuid::from(id))
+        .bind(offset as i32)
+        .bind(STREAM_CHUNK as i32)
+        .bind(modified_at)
+        .fetch_optional(pool)
+        .await?;
+    chunk
+        .filter(|chunk| !chunk.is_empty())
+        .ok_or(StorageError::Changed)
+}
+
+/// a row in `staged`, with a row in `staged_chunks` for every write
+///
+/// writes aren't retried, one that did go through would only fail as a duplicate then.
+#[derive(Debug)]
+struct PgStaged {
+    pool: PgPool,
+    id: Uuid,
+    /// chunks written so far, which is also the `seq` of the next one
+    chunks: i32,
+}
+
+#[async_trait]
+impl Staged for PgStaged {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), StorageError> {
+        sqlx::query(STAGE_WRITE)
+            .bind(self.id)
+            .bind(self.chunks)
+            .bind(chunk)
+            .execute(&self.pool)
+            .await?;
+        self.chunks += 1;
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>, id: Ulid, entry: NewEntry<'_>) -> Result<bool, StorageError> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(STAGE_COMMIT)
+            .bind(Uuid::from(id))
+            .bind(self.id)
+            .bind(entry.expires_at)
+            .bind(entry.encoding.to_column())
+            .bind(entry.size as i64)
+            .bind(entry.content_type)
+            .bind(entry.hash)
+            .bind(entry.created_at)
+            .bind(entry.encrypted)
+            .bind(entry.password_hash)
+            .bind(entry.dictionary_id.map(i64::from))
+            .execute(&mut *tx)
+            .await;
+        match result {
+            Ok(_) => {}
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                drop(tx);
+                self.abort().await?;
+                return Ok(false);
+            }
+            Err(err) => return Err(err.into()),
+        }
+        sqlx::query(STAGE_ABORT)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn abort(self: Box<Self>) -> Result<(), StorageError> {
+        sqlx::query(STAGE_ABORT)
+            .bind(self.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,653 @@
+use std::collections::HashMap;
+use std::io;
+
+use async_trait::async_trait;
+use aws_sdk_s3::Client;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::MetadataDirective;
+use chrono::{DateTime, Utc};
+use tracing::warn;
+use ulid::Ulid;
+
+use super::{Entry, Metadata, NewEntry, Replacement, Storage, StorageError, hex, ulid_time};
+use crate::encoding::Encoding;
+
+const ENCODING: &str = "encoding";
+const DICTIONARY_ID: &str = "dictionary-id";
+const SIZE: &str = "size";
+const HASH: &str = "hash";
+const EXPIRES_AT: &str = "expires-at";
+const CREATED_AT: &str = "created-at";
+const ENCRYPTED: &str = "encrypted";
+const PASSWORD_HASH: &str = "password-hash";
+const DELETED_AT: &str = "deleted-at";
+const MODIFIED_AT: &str = "modified-at";
+
+fn s3_error(err: impl Into<aws_sdk_s3::Error>) -> StorageError {
+    StorageError::S3(Box::new(err.into()))
+}
+
+/// what is kept in the user metadata of each object
+#[derive(Debug)]
+struct ObjectMetadata {
+    encoding: Encoding,
+    dictionary_id: Option<u32>,
+    size: u64,
+    /// hex encoded sha-256 of the uncompressed value
+    hash: String,
+    expires_at: Option<DateTime<Utc>>,
+    created_at: Option<DateTime<Utc>>,
+    encrypted: bool,
+    password_hash: Option<String>,
+    deleted_at: Option<DateTime<Utc>>,
+    modified_at: Option<DateTime<Utc>>,
+}
+
+fn parse_time(
+    metadata: Option<&HashMap<String, String>>,
+    key: &'static str,
+) -> Result<Option<DateTime<Utc>>, StorageError> {
+    metadata
+        .and_then(|metadata| metadata.get(key))
+        .map(|v| {
+            DateTime::parse_from_rfc3339(v)
+                .map(|v| v.with_timezone(&Utc))
+                .map_err(|_| StorageError::ObjectMetadata(key))
+        })
+        .transpose()
+}
+
+impl ObjectMetadata {
+    fn parse(metadata: Option<&HashMap<String, String>>) -> Result<Self, StorageError> {
+        let get = |key| metadata.and_then(|metadata| metadata.get(key));
+        Ok(Self {
+            encoding: Encoding::from_column(get(ENCODING).map(String::as_str))?,
+            dictionary_id: get(DICTIONARY_ID)
+                .map(|v| v.parse())
+                .transpose()
+                .map_err(|_| StorageError::ObjectMetadata(DICTIONARY_ID))?,
+            size: get(SIZE)
+                .and_then(|v| v.parse().ok())
+                .ok_or(StorageError::ObjectMetadata(SIZE))?,
+            hash: get(HASH)
+                .cloned()
+                .ok_or(StorageError::ObjectMetadata(HASH))?,
+            expires_at: parse_time(metadata, EXPIRES_AT)?,
+            created_at: parse_time(metadata, CREATED_AT)?,
+            encrypted: get(ENCRYPTED).is_some_and(|v| v == "true"),
+            password_hash: get(PASSWORD_HASH).cloned(),
+            deleted_at: parse_time(metadata, DELETED_AT)?,
+            modified_at: parse_time(metadata, MODIFIED_AT)?,
+        })
+    }
+
+    fn into_metadata(self, id: Ulid, length: u64, content_type: Option<String>) -> Metadata {
+        Metadata {
+            encoding: self.encoding,
+            dictionary_id: self.dictionary_id,
+            encrypted: self.encrypted,
+            password_hash: self.password_hash,
+            length,
+            size: Some(self.size),
+            content_type,
+            hash: Some(self.hash),
+            created_at: self.created_at.unwrap_or_else(|| ulid_time(id)),
+            modified_at: self.modified_at,
+            expires_at: self.expires_at,
+            // object metadata can't be changed without copying the object
+            views: None,
+        }
+    }
+
+    fn expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// whether the entry neither expired nor was soft-deleted
+    fn visible(&self) -> bool {
+        !self.expired() && self.deleted_at.is_none()
+    }
+}
+
+/// stores each entry as the object `<prefix><id>`, with its metadata as object metadata
+#[derive(Clone, Debug)]
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(client: Client, bucket: String, prefix: String) -> Self {
+        Self {
+            client,
+            bucket,
+            prefix,
+        }
+    }
+
+    fn key(&self, id: Ulid) -> String {
+        format!("{}{id}", self.prefix)
+    }
+
+    fn hash_key(&self, hash: &str) -> String {
+        format!("{}hashes/{hash}", self.prefix)
+    }
+
+    async fn head_object(&self, id: Ulid) -> Result<Option<HeadObjectOutput>, StorageError> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .send()
+            .await
+        {
+            Ok(output) => Ok(Some(output)),
+            Err(err) if err.as_service_error().is_some_and(|err| err.is_not_found()) => Ok(None),
+            Err(err) => Err(s3_error(err)),
+        }
+    }
+
+    /// fetches the metadata and content type of an object, even if it has expired
+    /// or was soft-deleted
+    async fn head(
+        &self,
+        id: Ulid,
+    ) -> Result<Option<(ObjectMetadata, u64, Option<String>)>, StorageError> {
+        let Some(output) = self.head_object(id).await? else {
+            return Ok(None);
+        };
+        let metadata = ObjectMetadata::parse(output.metadata())?;
+        let length = output.content_length().unwrap_or_default() as u64;
+        Ok(Some((
+            metadata,
+            length,
+            output.content_type().map(str::to_owned),
+        )))
+    }
+
+    /// sets `deleted_at` of an entry that isn't expired, returning whether it changed
+    async fn set_deleted_at(
+        &self,
+        id: Ulid,
+        deleted_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, StorageError> {
+        let Some(output) = self.head_object(id).await? else {
+            return Ok(false);
+        };
+        let metadata = ObjectMetadata::parse(output.metadata())?;
+        if metadata.expired() || metadata.deleted_at.is_some() == deleted_at.is_some() {
+            return Ok(false);
+        }
+
+        let mut user_metadata = output.metadata().cloned().unwrap_or_default();
+        match deleted_at {
+            Some(deleted_at) => {
+                user_metadata.insert(DELETED_AT.to_owned(), deleted_at.to_rfc3339())
+            }
+            None => user_metadata.remove(DELETED_AT),
+        };
+        self.rewrite_metadata(id, &output, user_metadata).await
+    }
+
+    /// replaces the metadata of the object `output` was fetched for, returning
+    /// false if it was deleted or replaced since
+    ///
+    /// object metadata can only be changed by copying the object onto itself.
+    async fn rewrite_metadata(
+        &self,
+        id: Ulid,
+        output: &HeadObjectOutput,
+        user_metadata: HashMap<String, String>,
+    ) -> Result<bool, StorageError> {
+        let key = self.key(id);
+        let mut request = self
+            .client
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .copy_source(format!("{}/{key}", self.bucket))
+            .metadata_directive(MetadataDirective::Replace)
+            .set_metadata(Some(user_metadata))
+            .set_content_type(output.content_type().map(str::to_owned));
+        // don't resurrect an object that was deleted or replaced in the meantime
+        if let Some(etag) = output.e_tag() {
+            request = request.copy_source_if_match(etag);
+        }
+        match request.send().await {
+            Ok(_) => Ok(true),
+            Err(err)
+                if err
+                    .raw_response()
+                    .is_some_and(|response| response.status().as_u16() == 412) =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(s3_error(err)),
+        }
+    }
+
+    /// reads the id a hash points to
+    async fn hash_target(&self, hash: &str) -> Result<Option<Ulid>, StorageError> {
+        let output = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.hash_key(hash))
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_no_such_key()) =>
+            {
+                return Ok(None);
+            }
+            Err(err) => return Err(s3_error(err)),
+        };
+        let id = output.body.collect().await.map_err(io::Error::from)?;
+        Ok(std::str::from_utf8(&id.into_bytes())
+            .ok()
+            .and_then(|id| Ulid::from_string(id).ok()))
+    }
+
+    /// removes the mapping from `hash` to `id`, unless another entry with the
+    /// same hash has taken it over
+    async fn remove_hash(&self, id: Ulid, hash: &str) -> Result<(), StorageError> {
+        if self.hash_target(hash).await? == Some(id) {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(self.hash_key(hash))
+                .send()
+                .await
+                .map_err(s3_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, id: Ulid, value: &[u8], entry: NewEntry<'_>) -> Result<bool, StorageError> {
+        let hash = hex(entry.hash);
+        let mut request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .body(ByteStream::from(value.to_vec()))
+            .set_content_type(entry.content_type.map(str::to_owned))
+            .metadata(SIZE, entry.size.to_string())
+            .metadata(HASH, &hash)
+            .metadata(CREATED_AT, entry.created_at.to_rfc3339())
+            .if_none_match("*");
+        if let Some(encoding) = entry.encoding.to_column() {
+            request = request.metadata(ENCODING, encoding);
+        }
+        if let Some(dictionary_id) = entry.dictionary_id {
+            request = request.metadata(DICTIONARY_ID, dictionary_id.to_string());
+        }
+        if let Some(expires_at) = entry.expires_at {
+            request = request.metadata(EXPIRES_AT, expires_at.to_rfc3339());
+        }
+        if entry.encrypted {
+            request = request.metadata(ENCRYPTED, "true");
+        }
+        if let Some(password_hash) = entry.password_hash {
+            request = request.metadata(PASSWORD_HASH, password_hash);
+        }
+        match request.send().await {
+            Ok(_) => {}
+            Err(err)
+                if err
+                    .raw_response()
+                    .is_some_and(|response| response.status().as_u16() == 412) =>
+            {
+                return Ok(false);
+            }
+            Err(err) => return Err(s3_error(err)),
+        }
+
+        // protected entries are never deduplicated against
+        if entry.password_hash.is_none() {
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(self.hash_key(&hash))
+                .body(ByteStream::from(id.to_string().into_bytes()))
+                .send()
+                .await
+                .map_err(s3_error)?;
+        }
+        Ok(true)
+    }
+
+    async fn find_duplicate(
+        &self,
+        hash: &[u8],
+        content_type: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Option<Ulid>, StorageError> {
+        let Some(id) = self.hash_target(&hex(hash)).await? else {
+            return Ok(None);
+        };
+        let Some((metadata, _, stored_content_type)) = self.head(id).await? else {
+            return Ok(None);
+        };
+
+        let lives_long_enough = match (metadata.expires_at, expires_at) {
+            (None, _) => true,
+            (Some(existing), Some(new)) => existing >= new,
+            (Some(_), None) => false,
+        };
+        if metadata.visible()
+            && metadata.password_hash.is_none()
+            && lives_long_enough
+            && stored_content_type.as_deref() == content_type
+        {
+            Ok(Some(id))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn get(&self, id: Ulid) -> Result<Option<Entry>, StorageError> {
+        let output = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_no_such_key()) =>
+            {
+                return Ok(None);
+            }
+            Err(err) => return Err(s3_error(err)),
+        };
+        let object_metadata = ObjectMetadata::parse(output.metadata())?;
+        if !object_metadata.visible() {
+            return Ok(None);
+        }
+        let content_type = output.content_type().map(str::to_owned);
+        let value = output
+            .body
+            .collect()
+            .await
+            .map_err(io::Error::from)?
+            .to_vec();
+        Ok(Some(Entry {
+            metadata: object_metadata.into_metadata(id, value.len() as u64, content_type),
+            value,
+        }))
+    }
+
+    async fn metadata(&self, id: Ulid) -> Result<Option<Metadata>, StorageError> {
+        let Some((metadata, length, content_type)) = self.head(id).await? else {
+            return Ok(None);
+        };
+        if !metadata.visible() {
+            return Ok(None);
+        }
+        Ok(Some(metadata.into_metadata(id, length, content_type)))
+    }
+
+    async fn replace(
+        &self,
+        id: Ulid,
+        value: &[u8],
+        replacement: Replacement<'_>,
+    ) -> Result<bool, StorageError> {
+        let Some(output) = self.head_object(id).await? else {
+            return Ok(false);
+        };
+        let metadata = ObjectMetadata::parse(output.metadata())?;
+        if !metadata.visible() {
+            return Ok(false);
+        }
+
+        let mut user_metadata = output.metadata().cloned().unwrap_or_default();
+        user_metadata.insert(SIZE.to_owned(), replacement.size.to_string());
+        user_metadata.insert(HASH.to_owned(), hex(replacement.hash));
+        user_metadata.insert(MODIFIED_AT.to_owned(), replacement.modified_at.to_rfc3339());
+        match replacement.encoding.to_column() {
+            Some(encoding) => user_metadata.insert(ENCODING.to_owned(), encoding.to_owned()),
+            None => user_metadata.remove(ENCODING),
+        };
+        match replacement.dictionary_id {
+            Some(dictionary_id) => {
+                user_metadata.insert(DICTIONARY_ID.to_owned(), dictionary_id.to_string())
+            }
+            None => user_metadata.remove(DICTIONARY_ID),
+        };
+        if replacement.encrypted {
+            user_metadata.insert(ENCRYPTED.to_owned(), "true".to_owned());
+        } else {
+            user_metadata.remove(ENCRYPTED);
+        }
+        let content_type = replacement
+            .content_type
+            .or(output.content_type())
+            .map(str::to_owned);
+        let mut request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .body(ByteStream::from(value.to_vec()))
+            .set_content_type(content_type)
+            .set_metadata(Some(user_metadata));
+        // don't resurrect an object that was deleted or replaced in the meantime
+        if let Some(etag) = output.e_tag() {
+            request = request.if_match(etag);
+        }
+        match request.send().await {
+            Ok(_) => {}
+            Err(err)
+                if err
+                    .raw_response()
+                    .is_some_and(|response| response.status().as_u16() == 412) =>
+            {
+                return Ok(false);
+            }
+            Err(err) => return Err(s3_error(err)),
+        }
+        // the replaced value isn't a duplicate of anything anymore
+        self.remove_hash(id, &metadata.hash).await?;
+        Ok(true)
+    }
+
+    async fn delete(&self, id: Ulid) -> Result<bool, StorageError> {
+        // deleting a missing object succeeds, so check whether it exists first
+        let Some((metadata, ..)) = self.head(id).await? else {
+            return Ok(false);
+        };
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .send()
+            .await
+            .map_err(s3_error)?;
+        self.remove_hash(id, &metadata.hash).await?;
+        Ok(true)
+    }
+
+    async fn soft_delete(&self, id: Ulid) -> Result<bool, StorageError> {
+        self.set_deleted_at(id, Some(Utc::now())).await
+    }
+
+    async fn restore(&self, id: Ulid) -> Result<bool, StorageError> {
+        self.set_deleted_at(id, None).await
+    }
+
+    async fn extend_expiry(
+        &self,
+        id: Ulid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, StorageError> {
+        let Some(output) = self.head_object(id).await? else {
+            return Ok(false);
+        };
+        let metadata = ObjectMetadata::parse(output.metadata())?;
+        let Some(current) = metadata.expires_at.filter(|_| metadata.visible()) else {
+            return Ok(false);
+        };
+        if current >= expires_at {
+            return Ok(true);
+        }
+
+        let mut user_metadata = output.metadata().cloned().unwrap_or_default();
+        user_metadata.insert(EXPIRES_AT.to_owned(), expires_at.to_rfc3339());
+        self.rewrite_metadata(id, &output, user_metadata).await
+    }
+
+    async fn list(
+        &self,
+        after: Option<Ulid>,
+        limit: usize,
+    ) -> Result<Vec<(Ulid, Metadata)>, StorageError> {
+        let mut entries = Vec::new();
+        // keys are listed in lexicographic order, which for ulids is the same as sorting the ids
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .delimiter("/")
+            .set_start_after(after.map(|after| self.key(after)))
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error)?;
+            for object in page.contents() {
+                if entries.len() >= limit {
+                    return Ok(entries);
+                }
+                let Some(id) = object
+                    .key()
+                    .and_then(|key| key.strip_prefix(&self.prefix))
+                    .and_then(|id| Ulid::from_string(id).ok())
+                else {
+                    continue;
+                };
+                if let Some(metadata) = self.metadata(id).await? {
+                    entries.push((id, metadata));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn total_length(&self) -> Result<u64, StorageError> {
+        let mut length = 0;
+        // the delimiter keeps the hash mappings out of the listing
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error)?;
+            length += page
+                .contents()
+                .iter()
+                .filter_map(|object| object.size())
+                .sum::<i64>() as u64;
+        }
+        Ok(length)
+    }
+
+    /// deletes the oldest entries, since object metadata can't be updated on every retrieval
+    async fn evict(&self, max: u64) -> Result<u64, StorageError> {
+        let mut ids = Vec::new();
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error)?;
+            ids.extend(page.contents().iter().filter_map(|object| {
+                object
+                    .key()
+                    .and_then(|key| key.strip_prefix(&self.prefix))
+                    .and_then(|id| Ulid::from_string(id).ok())
+            }));
+        }
+        // ulids sort by creation time
+        ids.sort_unstable();
+
+        let excess = ids.len().saturating_sub(max as usize);
+        for &id in &ids[..excess] {
+            self.delete(id).await?;
+        }
+        Ok(excess as u64)
+    }
+
+    async fn sweep(&self, deleted_before: DateTime<Utc>) -> Result<u64, StorageError> {
+        let mut swept = 0;
+        // the delimiter keeps the hash mappings out of the listing
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error)?;
+            for object in page.contents() {
+                let Some(id) = object
+                    .key()
+                    .and_then(|key| key.strip_prefix(&self.prefix))
+                    .and_then(|id| Ulid::from_string(id).ok())
+                else {
+                    continue;
+                };
+                match self.head(id).await {
+                    Ok(Some((metadata, ..)))
+                        if metadata.expired()
+                            || metadata
+                                .deleted_at
+                                .is_some_and(|deleted_at| deleted_at < deleted_before) =>
+                    {
+                        self.delete(id).await?;
+                        swept += 1;
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!("skipping {id} while sweeping: {err}"),
+                }
+            }
+        }
+        Ok(swept)
+    }
+
+    async fn health(&self) -> Result<(), StorageError> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+}
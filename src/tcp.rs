@@ -0,0 +1,34 @@
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+use tracing::warn;
+
+/// options set on every accepted tcp connection, from `TCP_NODELAY` and `TCP_KEEPALIVE_SECS`
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TcpOptions {
+    /// send small writes right away instead of waiting to coalesce them
+    pub nodelay: bool,
+    /// how long a connection can be idle before keepalive probes are sent, none if `None`
+    pub keepalive: Option<Duration>,
+}
+
+impl TcpOptions {
+    /// sets the options on `stream`, logging why if that fails
+    pub fn apply(&self, stream: &TcpStream) {
+        if let Err(err) = self.try_apply(stream) {
+            warn!("failed to set tcp options: {err}");
+        }
+    }
+
+    fn try_apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt::{self, Debug};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use axum::serve::Listener;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::{self, PemObject};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, ServerConfig, crypto::ring};
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, error, info};
+
+use crate::tcp::TcpOptions;
+
+/// how long a client gets to finish the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("failed to read pem file: {0}")]
+    Pem(#[from] pem::Error),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+/// a certificate and key, reloadable from the files they were loaded from
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl Tls {
+    pub fn load(cert: PathBuf, key: PathBuf) -> Result<Self, TlsError> {
+        let acceptor = RwLock::new(load_acceptor(&cert, &key)?);
+        Ok(Self {
+            cert,
+            key,
+            acceptor,
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor
+            .read()
+            .expect("tls acceptor lock poisoned")
+            .clone()
+    }
+
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let acceptor = load_acceptor(&self.cert, &self.key)?;
+        *self.acceptor.write().expect("tls acceptor lock poisoned") = acceptor;
+        Ok(())
+    }
+
+    /// reloads the certificate and key whenever SIGHUP is received
+    #[cfg(unix)]
+    pub async fn reload_on_sighup(self: Arc<Self>) {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to listen for SIGHUP, certificates won't be reloaded: {err}");
+                return;
+            }
+        };
+        while sighup.recv().await.is_some() {
+            match self.reload() {
+                Ok(()) => info!("reloaded tls certificate"),
+                Err(err) => error!("failed to reload tls certificate: {err}"),
+            }
+        }
+    }
+}
+
+impl Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls")
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+fn load_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, TlsError> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// accepts tcp connections and does the tls handshake for them
+///
+/// handshakes happen in the background, so slow clients don't hold up others.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, tls: Arc<Tls>, tcp: TcpOptions) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(accept_loop(listener, tls, tcp, tx));
+        Ok(Self {
+            connections: rx,
+            local_addr,
+        })
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    tls: Arc<Tls>,
+    tcp: TcpOptions,
+    tx: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    while !tx.is_closed() {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(err) => {
+                // usually running out of file descriptors, give it a moment
+                error!("failed to accept connection: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        tcp.apply(&stream);
+        let acceptor = tls.acceptor();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = tx.send((stream, addr)).await;
+                }
+                Ok(Err(err)) => debug!("tls handshake with {addr} failed: {err}"),
+                Err(_) => debug!("tls handshake with {addr} timed out"),
+            }
+        });
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // the accept loop only stops once this listener is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
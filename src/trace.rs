@@ -0,0 +1,57 @@
+use axum::body::Body;
+use axum::http::Request;
+use axum::http::header::{HeaderName, HeaderValue};
+use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
+use tower_http::request_id::{
+    MakeRequestId, PropagateRequestIdLayer, RequestId, SetRequestIdLayer,
+};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{Level, Span, info_span};
+use ulid::Ulid;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// gives requests without an `X-Request-Id` a new ulid
+#[derive(Clone, Copy, Debug)]
+pub struct MakeRequestUlid;
+
+impl MakeRequestId for MakeRequestUlid {
+    fn make_request_id<B>(&mut self, _: &Request<B>) -> Option<RequestId> {
+        let id =
+            HeaderValue::try_from(Ulid::new().to_string()).expect("ulids are valid header values");
+        Some(RequestId::new(id))
+    }
+}
+
+/// sets the request id, which must happen before the trace layer sees the request
+pub fn set_request_id() -> SetRequestIdLayer<MakeRequestUlid> {
+    SetRequestIdLayer::new(X_REQUEST_ID, MakeRequestUlid)
+}
+
+/// echoes the request id back in the response
+pub fn propagate_request_id() -> PropagateRequestIdLayer {
+    PropagateRequestIdLayer::new(X_REQUEST_ID)
+}
+
+fn make_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default();
+    info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id,
+    )
+}
+
+pub type Trace = TraceLayer<SharedClassifier<ServerErrorsAsFailures>, fn(&Request<Body>) -> Span>;
+
+/// logs every request and response, in a span with the request id
+pub fn trace_layer() -> Trace {
+    TraceLayer::new_for_http()
+        .make_span_with(make_span as fn(&Request<Body>) -> Span)
+        .on_response(DefaultOnResponse::new().level(Level::INFO))
+}
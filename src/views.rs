@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+use ulid::Ulid;
+
+use crate::storage::Storage;
+
+/// counts views in memory, so retrievals don't each have to write to storage
+#[derive(Default, Debug)]
+pub struct Views {
+    pending: Mutex<HashMap<Ulid, u64>>,
+}
+
+impl Views {
+    pub fn record(&self, id: Ulid) {
+        *self
+            .pending
+            .lock()
+            .expect("views lock poisoned")
+            .entry(id)
+            .or_default() += 1;
+    }
+
+    /// adds the views counted so far to storage, dropping them if that fails
+    pub async fn flush(&self, storage: &dyn Storage) {
+        let pending = mem::take(&mut *self.pending.lock().expect("views lock poisoned"));
+        if pending.is_empty() {
+            return;
+        }
+        let views: Vec<_> = pending.into_iter().collect();
+        if let Err(err) = storage.add_views(&views).await {
+            warn!("failed to record views of {} entries: {err}", views.len());
+        }
+    }
+
+    pub async fn flush_periodically(&self, storage: &dyn Storage, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.flush(storage).await;
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::header::HeaderName;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::warn;
+use ulid::Ulid;
+
+use crate::storage::hex;
+
+/// header with the hex encoded hmac-sha256 of the body, keyed with `WEBHOOK_SECRET`
+pub const X_WEBHOOK_SIGNATURE: HeaderName = HeaderName::from_static("x-webhook-signature");
+
+/// how many times a webhook is tried before giving up on it
+const ATTEMPTS: u32 = 3;
+/// backoff before the first retry, doubled for each one after
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Upload { id: String, size: u64 },
+    Delete { id: String },
+}
+
+impl Event {
+    pub fn upload(id: Ulid, size: u64) -> Self {
+        Self::Upload {
+            id: id.to_string(),
+            size,
+        }
+    }
+
+    pub fn delete(id: Ulid) -> Self {
+        Self::Delete { id: id.to_string() }
+    }
+}
+
+/// posts events to `WEBHOOK_URL`, in the background so they never hold up a response
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    url: Arc<str>,
+    secret: Option<Arc<[u8]>>,
+}
+
+impl Webhooks {
+    pub fn new(url: String, secret: Option<String>) -> reqwest::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+            url: url.into(),
+            secret: secret.map(|secret| secret.into_bytes().into()),
+        })
+    }
+
+    /// sends `event` in a spawned task, retrying a few times and logging failures
+    pub fn send(&self, event: Event) {
+        let body = serde_json::to_vec(&event).expect("events are always serializable");
+        let signature = self.secret.as_deref().map(|secret| {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any length");
+            mac.update(&body);
+            hex(&mac.finalize().into_bytes())
+        });
+        let webhooks = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            for attempt in 1..=ATTEMPTS {
+                let mut request = webhooks
+                    .client
+                    .post(&*webhooks.url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.clone());
+                if let Some(signature) = &signature {
+                    request = request.header(X_WEBHOOK_SIGNATURE, signature);
+                }
+                let err = match request.send().await.and_then(|res| res.error_for_status()) {
+                    Ok(_) => return,
+                    Err(err) => err,
+                };
+                if attempt == ATTEMPTS {
+                    warn!("webhook for {event:?} failed, giving up: {err}");
+                } else {
+                    warn!(
+                        "webhook for {event:?} failed (attempt {attempt}/{ATTEMPTS}), retrying in {}s: {err}",
+                        backoff.as_secs()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        });
+    }
+}
+
+impl Debug for Webhooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhooks")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}